@@ -1,33 +1,47 @@
 extern crate glib;
 extern crate gtk;
+extern crate gdk;
 extern crate gio;
 extern crate gdk_pixbuf;
 extern crate secret_service;
 extern crate libnotify;
+extern crate chrono;
+extern crate url;
+extern crate xdg;
 
 use self::secret_service::SecretService;
 use self::secret_service::EncryptionType;
 
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::channel;
-use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::Sender;
 use std::collections::HashMap;
 
 use self::gio::ApplicationExt;
 use self::gdk_pixbuf::Pixbuf;
 use self::gtk::prelude::*;
+use self::chrono::Local;
+use self::url::Url;
 
 use backend::Backend;
 use backend::BKCommand;
 use backend::BKResponse;
 use backend;
+use error;
 
 use types::Member;
 use types::Message;
+use types::MsgStatus;
 use types::Protocol;
 use types::Room;
 
+use mainloop;
+use settings::{Autoload, Settings};
+use util;
 use widgets;
+use widgets::LocalEcho;
 
 
 #[derive(Debug)]
@@ -45,10 +59,34 @@ const APP_ID: &'static str = "org.gnome.guillotine";
 pub struct AppOp {
     pub gtk_builder: gtk::Builder,
     pub backend: Sender<backend::BKCommand>,
+    pub uid: String,
     pub active_room: String,
+    /// Increased on every room change, responses of older generations
+    /// belong to a previous room and are ignored
+    pub room_gen: u64,
     pub members: HashMap<String, Member>,
     pub rooms: HashMap<String, Room>,
-    pub load_more_btn: gtk::Button,
+    /// First row of the message list, it shows the backfill state
+    pub timeline_status: gtk::Label,
+    /// true while waiting for older messages, or for the first ones
+    pub loading_more: bool,
+    /// true when the active room has no older messages
+    pub timeline_start: bool,
+    /// Scroll distance to the bottom to restore once the older messages
+    /// are on the list, so the view doesn't jump
+    pub scroll_from_bottom: Option<f64>,
+    /// Messages sent by this client not echoed back by the server yet
+    pub local_echoes: Vec<LocalEcho>,
+    /// true if the full member list of the active room was requested
+    pub member_list_loaded: bool,
+    /// Members waiting to be added to the members_treeview
+    pub member_queue: Vec<Member>,
+    pub settings: Settings,
+    /// Value of the login_server entry that wasn't written by the user,
+    /// the discovered server only replaces this one
+    pub server_autofill: String,
+    /// Timeout that hides the error_revealer, it's restarted by each error
+    pub error_timeout: Rc<RefCell<Option<glib::SourceId>>>,
 }
 
 #[derive(Debug)]
@@ -88,6 +126,53 @@ impl AppOp {
         self.connect(username, password, server_entry.get_text());
     }
 
+    pub fn discover_server(&self) {
+        let user_entry: gtk::Entry = self.gtk_builder
+            .get_object("login_username")
+            .expect("Can't find login_username in ui file.");
+        let info: gtk::Label = self.gtk_builder
+            .get_object("login_server_info")
+            .expect("Can't find login_server_info in ui file.");
+
+        let username = user_entry.get_text().unwrap_or(String::from(""));
+        if util::get_server_name(&username).is_none() {
+            info.hide();
+            return;
+        }
+
+        info.set_text("Looking for the homeserver...");
+        info.show();
+        self.backend.send(BKCommand::DiscoverServer(username)).unwrap();
+    }
+
+    pub fn set_discovered_server(&mut self, server: String, versions: Vec<String>) {
+        let server_entry: gtk::Entry = self.gtk_builder
+            .get_object("login_server")
+            .expect("Can't find login_server in ui file.");
+        let info: gtk::Label = self.gtk_builder
+            .get_object("login_server_info")
+            .expect("Can't find login_server_info in ui file.");
+
+        // the url written by the user is kept
+        let current = server_entry.get_text().unwrap_or(String::new());
+        if current.is_empty() || current == self.server_autofill {
+            server_entry.set_text(&server);
+            self.server_autofill = server.clone();
+        }
+        info.set_markup(&format!("<small>Homeserver: {}\nVersions: {}</small>",
+                                 server, versions.join(", ")));
+        info.show();
+    }
+
+    pub fn discover_server_error(&self) {
+        let info: gtk::Label = self.gtk_builder
+            .get_object("login_server_info")
+            .expect("Can't find login_server_info in ui file.");
+
+        info.set_markup("<small>Can't find the homeserver, write the server url</small>");
+        info.show();
+    }
+
     pub fn register(&self) {
         let user_entry: gtk::Entry = self.gtk_builder
             .get_object("register_username")
@@ -158,8 +243,7 @@ impl AppOp {
 
         self.store_pass(username.clone(), password.clone(), server_url.clone())
             .unwrap_or_else(|_| {
-                // TODO: show an error
-                println!("Error: Can't store the password using libsecret");
+                self.show_error(String::from("Can't store the password using libsecret"));
             });
 
         self.show_user_loading();
@@ -199,9 +283,7 @@ impl AppOp {
             .get_object::<gtk::Image>("profile_image")
             .expect("Can't find profile_image in ui file.");
 
-        if let Ok(pixbuf) = Pixbuf::new_from_file_at_size(fname, 20, 20) {
-            image.set_from_pixbuf(&pixbuf);
-        } else {
+        if !widgets::set_image_file(&image, fname, 20, true) {
             image.set_from_icon_name("image-missing", 2);
         }
 
@@ -231,6 +313,103 @@ impl AppOp {
         user_menu.hide();
     }
 
+    pub fn show_error(&self, msg: String) {
+        let revealer = self.gtk_builder
+            .get_object::<gtk::Revealer>("error_revealer")
+            .expect("Can't find error_revealer in ui file.");
+        self.gtk_builder
+            .get_object::<gtk::Label>("error_label")
+            .expect("Can't find error_label in ui file.")
+            .set_text(&msg);
+
+        revealer.set_reveal_child(true);
+
+        // the timeout of an older error would hide this one too soon
+        if let Some(id) = self.error_timeout.borrow_mut().take() {
+            glib::source_remove(id);
+        }
+        let timeout = self.error_timeout.clone();
+        let id = gtk::timeout_add(5000, move || {
+            timeout.borrow_mut().take();
+            revealer.set_reveal_child(false);
+            gtk::Continue(false)
+        });
+        *self.error_timeout.borrow_mut() = Some(id);
+    }
+
+    pub fn hide_error(&self) {
+        self.gtk_builder
+            .get_object::<gtk::Revealer>("error_revealer")
+            .expect("Can't find error_revealer in ui file.")
+            .set_reveal_child(false);
+    }
+
+    pub fn login_error(&self, err: error::Error) {
+        self.show_username();
+        self.room_panel(RoomPanel::NoRoom);
+        self.show_error(format!("Can't login: {}", err));
+    }
+
+    /// Changes the media cache size limit to @size MB
+    pub fn set_cache_size(&mut self, size: u64) {
+        if self.settings.media_cache_size == size {
+            return;
+        }
+
+        self.settings.media_cache_size = size;
+        if let Err(err) = self.settings.save() {
+            self.show_error(format!("Can't save the settings: {}", err));
+        }
+        self.backend.send(BKCommand::SetCacheSize(self.settings.media_cache_bytes())).unwrap();
+    }
+
+    /// Enables or disables the autoplay of the animated images, it's
+    /// used by the messages shown after the change
+    pub fn set_autoplay(&mut self, autoplay: bool) {
+        self.settings.autoplay_animations = autoplay;
+        if let Err(err) = self.settings.save() {
+            self.show_error(format!("Can't save the settings: {}", err));
+        }
+    }
+
+    /// Sets when the message images and avatars are loaded without
+    /// asking, it's used by the messages shown after the change
+    pub fn set_autoload(&mut self, autoload: Autoload) {
+        self.settings.media_autoload = autoload;
+        if let Err(err) = self.settings.save() {
+            self.show_error(format!("Can't save the settings: {}", err));
+        }
+        self.backend.send(BKCommand::SetAutoload(autoload)).unwrap();
+    }
+
+    /// Sets the size limit, in MB, of the images loaded without asking
+    pub fn set_autoload_max_size(&mut self, size: u64) {
+        if self.settings.autoload_max_size == size {
+            return;
+        }
+
+        self.settings.autoload_max_size = size;
+        if let Err(err) = self.settings.save() {
+            self.show_error(format!("Can't save the settings: {}", err));
+        }
+    }
+
+    pub fn clear_cache(&self) {
+        self.gtk_builder
+            .get_object::<gtk::Button>("clear_cache_button")
+            .expect("Can't find clear_cache_button in ui file.")
+            .set_sensitive(false);
+
+        self.backend.send(BKCommand::ClearCache).unwrap();
+    }
+
+    pub fn cache_cleared(&self) {
+        self.gtk_builder
+            .get_object::<gtk::Button>("clear_cache_button")
+            .expect("Can't find clear_cache_button in ui file.")
+            .set_sensitive(true);
+    }
+
     pub fn disconnect(&self) {
         self.backend.send(BKCommand::ShutDown).unwrap();
     }
@@ -326,6 +505,24 @@ impl AppOp {
         });
     }
 
+    pub fn set_reconnecting(&self, delay: Option<u64>) {
+        let bx = self.gtk_builder
+            .get_object::<gtk::Box>("reconnecting_box")
+            .expect("Can't find reconnecting_box in ui file.");
+        let label = self.gtk_builder
+            .get_object::<gtk::Label>("reconnecting_label")
+            .expect("Can't find reconnecting_label in ui file.");
+
+        match delay {
+            Some(ms) => {
+                let secs = (ms + 999) / 1000;
+                label.set_tooltip_text(&format!("Connection lost, retrying in {}s", secs)[..]);
+                bx.show();
+            }
+            None => bx.hide(),
+        };
+    }
+
     pub fn set_rooms(&mut self, rooms: Vec<Room>, def: Option<Room>) {
         let store: gtk::TreeStore = self.gtk_builder
             .get_object("rooms_tree_store")
@@ -377,6 +574,20 @@ impl AppOp {
 
     pub fn set_active_room(&mut self, room: String, name: String) {
         self.active_room = room;
+        self.room_gen += 1;
+
+        // the first messages are loaded with the room
+        self.loading_more = true;
+        self.timeline_start = false;
+        self.scroll_from_bottom = None;
+        self.timeline_status.set_text("");
+
+        // the rows are removed with the room, the pending ones are shown
+        // again after loading the new room
+        self.local_echoes.retain(|e| match e.status.get() {
+            MsgStatus::Queued | MsgStatus::Sending | MsgStatus::Failed => true,
+            _ => false,
+        });
 
         self.room_panel(RoomPanel::Loading);
 
@@ -388,6 +599,8 @@ impl AppOp {
         }
 
         self.members.clear();
+        self.member_list_loaded = false;
+        self.member_queue.clear();
         let members = self.gtk_builder
             .get_object::<gtk::ListStore>("members_store")
             .expect("Can't find members_store in ui file.");
@@ -399,11 +612,16 @@ impl AppOp {
         name_label.set_text(&name);
 
         // getting room details
-        self.backend.send(BKCommand::SetRoom(self.active_room.clone())).unwrap();
+        self.backend.send(BKCommand::SetRoom(self.active_room.clone(), self.room_gen)).unwrap();
+
+        if self.members_visible() {
+            self.load_member_list();
+        }
     }
 
-    pub fn get_room_messages(&self) {
-        self.backend.send(BKCommand::GetRoomMessages(self.active_room.clone())).unwrap();
+    /// Checks if a room scoped response is for the room that's shown now
+    pub fn is_active_room(&self, resp: &BKResponse) -> bool {
+        resp.is_for_room(&self.active_room, self.room_gen)
     }
 
     pub fn set_room_detail(&self, key: String, value: String) {
@@ -431,15 +649,22 @@ impl AppOp {
             .get_object::<gtk::Image>("room_image")
             .expect("Can't find room_image in ui file.");
 
-        if !avatar.is_empty() {
-            if let Ok(pixbuf) = Pixbuf::new_from_file_at_size(&avatar, 40, 40) {
-                image.set_from_pixbuf(&pixbuf);
-            }
-        } else {
+        if avatar.is_empty() || !widgets::set_image_file(&image, &avatar, 40, true) {
             image.set_from_icon_name("image-missing", 5);
         }
     }
 
+    /// The avatars are downloaded again for the new @scale factor, the
+    /// widgets of the messages update their own avatars
+    pub fn scale_changed(&self, scale: i32) {
+        self.backend.send(BKCommand::SetScaleFactor(scale)).unwrap();
+        self.backend.send(BKCommand::GetAvatar).unwrap();
+        if !self.active_room.is_empty() {
+            let room = self.active_room.clone();
+            self.backend.send(BKCommand::GetRoomAvatar(room, self.room_gen)).unwrap();
+        }
+    }
+
     pub fn scroll_down(&self) {
         let scroll = self.gtk_builder
             .get_object::<gtk::ScrolledWindow>("messages_scroll")
@@ -500,11 +725,21 @@ impl AppOp {
     }
 
     pub fn mark_as_read(&self, msg: &Message) {
+        if msg.event_id.is_empty() {
+            return;
+        }
+
         self.backend.send(BKCommand::MarkAsRead(msg.room.clone(),
-                                                msg.id.clone())).unwrap();
+                                                msg.event_id.clone())).unwrap();
     }
 
+    /// Adds a known member, used to show the message senders
     pub fn add_room_member(&mut self, m: Member) {
+        self.members.insert(m.uid.clone(), m);
+    }
+
+    /// Adds a member to the members_treeview
+    pub fn add_member_row(&mut self, m: Member) {
         let store: gtk::ListStore = self.gtk_builder
             .get_object("members_store")
             .expect("Couldn't find members_store in ui file.");
@@ -516,13 +751,208 @@ impl AppOp {
         self.members.insert(m.uid.clone(), m);
     }
 
+    /// Asks for the full member list of the active room, it's only loaded
+    /// when the member sidebar is shown
+    pub fn load_member_list(&mut self) {
+        if self.active_room.is_empty() || self.member_list_loaded {
+            return;
+        }
+
+        self.member_list_loaded = true;
+        let room = self.active_room.clone();
+        self.backend.send(BKCommand::GetRoomMemberList(room, self.room_gen)).unwrap();
+    }
+
+    pub fn members_visible(&self) -> bool {
+        self.gtk_builder
+            .get_object::<gtk::Stack>("sidebar_stack")
+            .expect("Can't find sidebar_stack in ui file.")
+            .get_visible_child_name()
+            .map_or(false, |n| n == "sidebar_members")
+    }
+
     pub fn member_clicked(&self, uid: String) {
         println!("member clicked: {}, {:?}", uid, self.members.get(&uid));
     }
 
-    pub fn send_message(&self, msg: String) {
-        let room = self.active_room.clone();
-        self.backend.send(BKCommand::SendMsg(room, msg)).unwrap();
+    pub fn send_message(&mut self, msg: String) {
+        if self.active_room.is_empty() || msg.is_empty() {
+            return;
+        }
+
+        let m = Message {
+            sender: self.uid.clone(),
+            mtype: String::from("m.text"),
+            body: msg,
+            date: Local::now(),
+            room: self.active_room.clone(),
+            thumb: String::new(),
+            url: String::new(),
+            info: json!(null),
+            event_id: String::new(),
+            txn_id: util::new_txn_id(),
+            unsigned: json!(null),
+        };
+
+        let echo = LocalEcho::new(&m, self);
+        self.add_local_echo(&echo);
+        self.local_echoes.push(echo);
+        self.scroll_down();
+
+        self.backend.send(BKCommand::SendMsg(m)).unwrap();
+    }
+
+    /// Sends the file @path to the active room, the @caption is the
+    /// message body if it isn't empty
+    pub fn send_attachment(&mut self, path: String, caption: String, compress: bool) {
+        if self.active_room.is_empty() {
+            return;
+        }
+
+        let mimetype = util::guess_mimetype(&path);
+        let mtype = util::msgtype_for(&mimetype);
+        let body = match caption.trim() {
+            "" => util::file_name(&path),
+            c => String::from(c),
+        };
+
+        let m = Message {
+            sender: self.uid.clone(),
+            mtype: String::from(mtype),
+            body: body,
+            date: Local::now(),
+            room: self.active_room.clone(),
+            // the local file is the preview until it's uploaded
+            thumb: match mtype {
+                "m.image" => path.clone(),
+                _ => String::new(),
+            },
+            url: String::new(),
+            info: json!(null),
+            event_id: String::new(),
+            txn_id: util::new_txn_id(),
+            unsigned: json!(null),
+        };
+
+        let mut echo = LocalEcho::new(&m, self);
+        echo.attachment = Some((path.clone(), compress));
+        self.add_local_echo(&echo);
+        self.local_echoes.push(echo);
+        self.scroll_down();
+
+        self.backend.send(BKCommand::AttachFile(m, path, compress)).unwrap();
+    }
+
+    /// Keeps the mxc url and the info of the uploaded attachment, so
+    /// it isn't uploaded again if the message has to be resent
+    pub fn attachment_uploaded(&mut self, msg: Message) {
+        if let Some(echo) = self.local_echoes.iter_mut().find(|e| e.msg.txn_id == msg.txn_id) {
+            echo.msg = msg;
+            // the local file isn't needed to resend it, the image is
+            // downloaded if the row is built again
+            let pasted = match echo.attachment {
+                Some((ref path, _)) => util::remove_pasted_image(path),
+                None => false,
+            };
+            if pasted {
+                echo.msg.thumb = String::new();
+            }
+        }
+    }
+
+    /// Stores the image pasted in the msg_entry in a file to send it
+    pub fn save_pasted_image(&self, pixbuf: &Pixbuf) -> Option<String> {
+        let fname = format!("uploads/pasted-{}.png", util::new_txn_id());
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+        let path = xdg_dirs.place_cache_file(fname)
+            .ok()
+            .and_then(|p| p.to_str().map(String::from))?;
+
+        match pixbuf.savev(&path, "png", &[]) {
+            Ok(_) => Some(path),
+            Err(err) => {
+                self.show_error(format!("Can't paste the image: {}", err));
+                None
+            }
+        }
+    }
+
+    pub fn add_local_echo(&self, echo: &LocalEcho) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+
+        messages.add(&echo.row);
+        echo.set_status(echo.status.get(), &self.backend);
+    }
+
+    /// Shows the local echoes of the active room that aren't sent yet,
+    /// after loading the room
+    pub fn show_local_echoes(&mut self) {
+        let mut echoes = self.local_echoes.clone();
+        for echo in echoes.iter_mut().filter(|e| e.msg.room == self.active_room) {
+            echo.rebuild(self);
+            self.add_local_echo(echo);
+        }
+        self.local_echoes = echoes;
+    }
+
+    pub fn msg_sent(&mut self, txn_id: String, evid: String) {
+        if let Some(echo) = self.local_echoes.iter_mut().find(|e| e.msg.txn_id == txn_id) {
+            echo.msg.event_id = evid;
+            echo.set_status(MsgStatus::Sent, &self.backend);
+        }
+    }
+
+    pub fn msg_queued(&mut self, txn_id: String) {
+        if let Some(echo) = self.local_echoes.iter().find(|e| e.msg.txn_id == txn_id) {
+            echo.set_status(MsgStatus::Queued, &self.backend);
+        }
+    }
+
+    /// Shows the messages of the stored outbox, they're sent after the
+    /// first sync
+    pub fn set_outbox(&mut self, msgs: Vec<Message>) {
+        for m in msgs {
+            if self.local_echoes.iter().any(|e| e.msg.txn_id == m.txn_id) {
+                continue;
+            }
+
+            let echo = LocalEcho::new(&m, self);
+            echo.status.set(MsgStatus::Queued);
+            if m.room == self.active_room {
+                self.add_local_echo(&echo);
+            }
+            self.local_echoes.push(echo);
+        }
+    }
+
+    pub fn msg_send_error(&mut self, txn_id: String, err: error::Error) {
+        if let Some(echo) = self.local_echoes.iter().find(|e| e.msg.txn_id == txn_id) {
+            echo.set_status(MsgStatus::Failed, &self.backend);
+        }
+        self.show_error(format!("Can't send the message: {}", err));
+    }
+
+    /// Removes the local echo of the remote @msg, returns false if there's
+    /// no local echo for it so the message should be shown
+    pub fn reconcile_local_echo(&mut self, msg: &Message) -> bool {
+        if msg.txn_id.is_empty() {
+            return false;
+        }
+
+        let pos = match self.local_echoes.iter().position(|e| e.msg.txn_id == msg.txn_id) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        let echo = self.local_echoes.remove(pos);
+        // the message was sent even if it failed for us
+        if echo.status.get() == MsgStatus::Canceled {
+            return false;
+        }
+        echo.set_status(MsgStatus::Sent, &self.backend);
+        true
     }
 
     pub fn hide_members(&self) {
@@ -532,21 +962,49 @@ impl AppOp {
             .set_visible_child_name("sidebar_hidden");
     }
 
-    pub fn show_members(&self) {
+    pub fn show_members(&mut self) {
         self.gtk_builder
             .get_object::<gtk::Stack>("sidebar_stack")
             .expect("Can't find sidebar_stack in ui file.")
             .set_visible_child_name("sidebar_members");
+
+        self.load_member_list();
     }
 
-    pub fn load_more_messages(&self) {
+    pub fn load_more_messages(&mut self) {
+        if self.active_room.is_empty() || self.loading_more || self.timeline_start {
+            return;
+        }
+
+        self.loading_more = true;
+        self.timeline_status.set_text("Loading older messages…");
+
         let room = self.active_room.clone();
-        self.load_more_btn.set_label("loading...");
-        self.backend.send(BKCommand::GetRoomMessagesTo(room)).unwrap();
+        self.backend.send(BKCommand::GetRoomMessagesTo(room, self.room_gen)).unwrap();
     }
 
-    pub fn load_more_normal(&self) {
-        self.load_more_btn.set_label("load more messages");
+    pub fn load_more_normal(&mut self) {
+        self.loading_more = false;
+        if !self.timeline_start {
+            self.timeline_status.set_text("");
+        }
+    }
+
+    pub fn set_timeline_start(&mut self) {
+        self.timeline_start = true;
+        self.timeline_status.set_text("This is the beginning of the room");
+    }
+
+    /// Keeps the scroll distance to the bottom, called before adding
+    /// messages on top
+    pub fn keep_scroll_position(&mut self) {
+        let scroll = self.gtk_builder
+            .get_object::<gtk::ScrolledWindow>("messages_scroll")
+            .expect("Can't find message_scroll in ui file.");
+
+        if let Some(adj) = scroll.get_vadjustment() {
+            self.scroll_from_bottom = Some(adj.get_upper() - adj.get_value());
+        }
     }
 
     pub fn init_protocols(&self) {
@@ -639,21 +1097,22 @@ impl AppOp {
         let mut body = msg.body.clone();
         body.truncate(80);
 
-        let (tx, rx): (Sender<(String, String)>, Receiver<(String, String)>) = channel();
-        self.backend.send(BKCommand::GetUserInfoAsync(msg.sender.clone(), tx)).unwrap();
-        gtk::timeout_add(50, move || match rx.try_recv() {
-            Err(_) => gtk::Continue(true),
-            Ok((name, avatar)) => {
-                let summary = format!("@{} / {}", name, roomname);
-                let n = libnotify::Notification::new(&summary, Some(&body[..]), Some(&avatar[..]));
-                n.show().unwrap();
-                gtk::Continue(false)
-            }
+        let (tx, rx) = mainloop::channel::<(String, String)>();
+        let room = msg.room.clone();
+        self.backend.send(BKCommand::GetUserInfoAsync(room, None, msg.sender.clone(), tx)).unwrap();
+        rx.attach(move |(name, avatar)| {
+            let summary = format!("@{} / {}", name, roomname);
+            let n = libnotify::Notification::new(&summary, Some(&body[..]), Some(&avatar[..]));
+            n.show().unwrap();
         });
     }
 
-    pub fn show_room_messages(&self, msgs: Vec<Message>, init: bool) {
+    pub fn show_room_messages(&mut self, msgs: Vec<Message>, init: bool) {
         for msg in msgs.iter() {
+            if self.reconcile_local_echo(msg) {
+                continue;
+            }
+
             self.add_room_message(msg, MsgPos::Bottom);
             if !init {
                 self.notify(msg);
@@ -669,11 +1128,43 @@ impl AppOp {
         }
 
         if init {
+            self.show_local_echoes();
             self.room_panel(RoomPanel::Room);
+            self.load_more_normal();
         }
     }
 }
 
+/// Number of members added to the members_treeview in each main loop
+/// iteration
+const MEMBERS_BATCH: usize = 100;
+
+/// Adds the queued members to the members_treeview in small batches, so
+/// big rooms don't block the UI
+fn add_queued_members(op: Arc<Mutex<AppOp>>) {
+    gtk::idle_add(move || {
+        let mut op = op.lock().unwrap();
+        let n = cmp::min(op.member_queue.len(), MEMBERS_BATCH);
+        let batch: Vec<Member> = op.member_queue.drain(..n).collect();
+        for m in batch {
+            op.add_member_row(m);
+        }
+
+        gtk::Continue(!op.member_queue.is_empty())
+    });
+}
+
+/// Shows the attach_dialog for the file @path, the file is sent to the
+/// active room if the user accepts
+fn show_attach_dialog(op: &Arc<Mutex<AppOp>>, window: &gtk::Window, path: String) {
+    let op = op.clone();
+    let p = path.clone();
+    let p2 = path.clone();
+    widgets::attach_dialog(window, &path, move |caption, compress| {
+        op.lock().unwrap().send_attachment(p.clone(), caption, compress);
+    }, move || { util::remove_pasted_image(&p2); });
+}
+
 /// State for the main thread.
 ///
 /// It takes care of starting up the application and for loading and accessing the
@@ -694,96 +1185,199 @@ impl App {
         let gtk_app = gtk::Application::new(Some(APP_ID), gio::ApplicationFlags::empty())
             .expect("Failed to initialize GtkApplication");
 
-        let (tx, rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
+        let (tx, rx) = mainloop::channel::<BKResponse>();
 
         let bk = Backend::new(tx);
         let apptx = bk.run();
 
         let gtk_builder = gtk::Builder::new_from_file("res/main_window.glade");
+        let server_entry: gtk::Entry = gtk_builder
+            .get_object("login_server")
+            .expect("Can't find login_server in ui file.");
         let op = Arc::new(Mutex::new(AppOp {
             gtk_builder: gtk_builder.clone(),
-            load_more_btn: gtk::Button::new_with_label("Load more messages"),
+            timeline_status: gtk::Label::new(None),
+            loading_more: false,
+            timeline_start: false,
+            scroll_from_bottom: None,
+            local_echoes: vec![],
+            member_list_loaded: false,
+            member_queue: vec![],
+            settings: Settings::load(),
             backend: apptx,
+            uid: String::from(""),
             active_room: String::from(""),
+            room_gen: 0,
             members: HashMap::new(),
             rooms: HashMap::new(),
+            server_autofill: server_entry.get_text().unwrap_or(String::new()),
+            error_timeout: Rc::new(RefCell::new(None)),
         }));
 
+        {
+            let op = op.lock().unwrap();
+            op.backend.send(BKCommand::SetCacheSize(op.settings.media_cache_bytes())).unwrap();
+            op.backend.send(BKCommand::SetAutoload(op.settings.media_autoload)).unwrap();
+        }
+
         let theop = op.clone();
-        gtk::timeout_add(500, move || {
-            let recv = rx.try_recv();
-            match recv {
-                Ok(BKResponse::Token(uid, _)) => {
+        rx.attach(move |resp| {
+            // the responses of the previous room don't touch the new one
+            if !theop.lock().unwrap().is_active_room(&resp) {
+                return;
+            }
+
+            match resp {
+                BKResponse::Token(uid, _) => {
+                    theop.lock().unwrap().uid = uid.clone();
                     theop.lock().unwrap().set_username(&uid);
                     theop.lock().unwrap().get_username();
                     theop.lock().unwrap().sync();
 
                     theop.lock().unwrap().init_protocols();
                 }
-                Ok(BKResponse::Name(username)) => {
+                BKResponse::ServerDiscovered(server, versions) => {
+                    theop.lock().unwrap().set_discovered_server(server, versions);
+                }
+                BKResponse::DiscoverServerError(_) => {
+                    theop.lock().unwrap().discover_server_error();
+                }
+                BKResponse::Name(username) => {
                     theop.lock().unwrap().set_username(&username);
                 }
-                Ok(BKResponse::Avatar(path)) => {
+                BKResponse::Avatar(path) => {
                     theop.lock().unwrap().set_avatar(&path);
                 }
-                Ok(BKResponse::Sync) => {
+                BKResponse::Sync => {
                     println!("SYNC");
+                    theop.lock().unwrap().set_reconnecting(None);
                     theop.lock().unwrap().sync();
                 }
-                Ok(BKResponse::Rooms(rooms, default)) => {
+                BKResponse::Reconnecting(delay) => {
+                    theop.lock().unwrap().set_reconnecting(Some(delay));
+                }
+                BKResponse::Rooms(rooms, default) => {
                     theop.lock().unwrap().set_rooms(rooms, default);
                 }
-                Ok(BKResponse::RoomDetail(key, value)) => {
+                BKResponse::RoomDetail(_, _, key, value) => {
                     theop.lock().unwrap().set_room_detail(key, value);
                 }
-                Ok(BKResponse::RoomAvatar(avatar)) => {
+                BKResponse::RoomAvatar(_, _, avatar) => {
                     theop.lock().unwrap().set_room_avatar(avatar);
                 }
-                Ok(BKResponse::RoomMessages(msgs)) => {
+                BKResponse::RoomMessages(msgs) => {
                     theop.lock().unwrap().show_room_messages(msgs, false);
                 }
-                Ok(BKResponse::RoomMessagesInit(msgs)) => {
+                BKResponse::RoomMessagesInit(_, _, msgs) => {
                     theop.lock().unwrap().show_room_messages(msgs, true);
                 }
-                Ok(BKResponse::RoomMessagesTo(msgs)) => {
+                BKResponse::RoomMessagesTo(_, _, msgs) => {
+                    theop.lock().unwrap().keep_scroll_position();
                     for msg in msgs.iter().rev() {
                         theop.lock().unwrap().add_room_message(msg, MsgPos::Top);
                     }
                     theop.lock().unwrap().load_more_normal();
                 }
-                Ok(BKResponse::RoomMembers(members)) => {
+                BKResponse::RoomTimelineStart(_, _) => {
+                    theop.lock().unwrap().set_timeline_start();
+                }
+                BKResponse::RoomMembers(_, _, members) => {
+                    for m in members {
+                        theop.lock().unwrap().add_room_member(m);
+                    }
+                }
+                BKResponse::RoomMemberList(_, _, members) => {
                     let mut ms = members;
                     ms.sort_by(|x, y| {
                         x.get_alias().to_lowercase().cmp(&y.get_alias().to_lowercase())
                     });
-                    for m in ms {
-                        theop.lock().unwrap().add_room_member(m);
-                    }
-                    theop.lock().unwrap().get_room_messages();
+                    theop.lock().unwrap().member_queue = ms;
+                    add_queued_members(theop.clone());
+                }
+                BKResponse::SentMsg(txn_id, evid) => {
+                    theop.lock().unwrap().msg_sent(txn_id, evid);
+                }
+                BKResponse::AttachmentUploaded(msg) => {
+                    theop.lock().unwrap().attachment_uploaded(msg);
+                }
+                BKResponse::MsgQueued(txn_id) => {
+                    theop.lock().unwrap().msg_queued(txn_id);
+                }
+                BKResponse::Outbox(msgs) => {
+                    theop.lock().unwrap().set_outbox(msgs);
                 }
-                Ok(BKResponse::SendMsg) => {}
-                Ok(BKResponse::DirectoryProtocols(protocols)) => {
+                BKResponse::DirectoryProtocols(protocols) => {
                     theop.lock().unwrap().set_protocols(protocols);
                 }
-                Ok(BKResponse::DirectorySearch(rooms)) => {
+                BKResponse::DirectorySearch(rooms) => {
                     for room in rooms {
                         theop.lock().unwrap().set_directory_room(room);
                     }
                 }
-                Ok(BKResponse::JoinRoom) => {
+                BKResponse::JoinRoom => {
                     theop.lock().unwrap().reload_rooms();
                 }
-                Ok(BKResponse::MarkedAsRead(r, _)) => {
+                BKResponse::MarkedAsRead(r, _) => {
                     theop.lock().unwrap().update_room_notifications(&r, |_| 0);
                 }
+                BKResponse::CacheCleared => {
+                    theop.lock().unwrap().cache_cleared();
+                }
                 // errors
-                Ok(err) => {
-                    println!("Query error: {:?}", err);
+                BKResponse::LoginError(err) |
+                BKResponse::GuestLoginError(err) => {
+                    theop.lock().unwrap().login_error(err);
+                }
+                BKResponse::SyncError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't sync: {}", err));
+                }
+                BKResponse::SendMsgError(txn_id, err) => {
+                    theop.lock().unwrap().msg_send_error(txn_id, err);
+                }
+                BKResponse::RoomMessagesError(_, _, err) => {
+                    theop.lock().unwrap().load_more_normal();
+                    theop.lock().unwrap().show_error(format!("Can't load the messages: {}", err));
+                }
+                BKResponse::SetRoomError(_, _, err) |
+                BKResponse::RoomDetailError(_, _, err) |
+                BKResponse::RoomMembersError(_, _, err) => {
+                    theop.lock().unwrap().show_error(format!("Can't load the room: {}", err));
+                }
+                BKResponse::JoinRoomError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't join the room: {}", err));
+                }
+                BKResponse::ClearCacheError(err) => {
+                    theop.lock().unwrap().cache_cleared();
+                    theop.lock().unwrap().show_error(format!("Can't clear the cache: {}", err));
+                }
+                BKResponse::SyncFilterError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't upload the sync filter: {}", err));
+                }
+                BKResponse::StoreError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't save the rooms: {}", err));
+                }
+                BKResponse::OutboxError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't save the unsent messages: {}", err));
+                }
+                BKResponse::DirectoryError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't search rooms: {}", err));
+                }
+                BKResponse::UserNameError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't load the user name: {}", err));
+                }
+                BKResponse::AvatarError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't load the avatar: {}", err));
+                }
+                BKResponse::RoomAvatarError(_, _, err) => {
+                    theop.lock().unwrap().show_error(format!("Can't load the room avatar: {}", err));
+                }
+                BKResponse::MarkAsReadError(err) => {
+                    theop.lock().unwrap().show_error(format!("Can't mark the room as read: {}", err));
+                }
+                BKResponse::CommandError(err) => {
+                    theop.lock().unwrap().show_error(format!("Request failed: {}", err));
                 }
-                Err(_) => {}
             };
-
-            gtk::Continue(true)
         });
 
         let app = App {
@@ -814,14 +1408,24 @@ impl App {
             Inhibit(false)
         });
 
+        // the scale is set before anything is downloaded, and the window is
+        // notified before its children when it changes
+        let scale = window.get_scale_factor();
+        self.op.lock().unwrap().backend.send(BKCommand::SetScaleFactor(scale)).unwrap();
+        let op = self.op.clone();
+        window.connect_property_scale_factor_notify(move |w| {
+            op.lock().unwrap().scale_changed(w.get_scale_factor());
+        });
+
         self.gtk_app.connect_startup(move |app| { window.set_application(app); });
 
-        self.create_load_more_btn();
+        self.create_timeline_status();
 
         self.connect_user_button();
         self.connect_login_button();
         self.connect_register_button();
         self.connect_guest_button();
+        self.connect_settings();
 
         self.connect_room_treeview();
         self.connect_member_treeview();
@@ -829,8 +1433,20 @@ impl App {
         self.connect_msg_scroll();
 
         self.connect_send();
+        self.connect_attachments();
 
         self.connect_directory();
+
+        self.connect_error_button();
+    }
+
+    fn connect_error_button(&self) {
+        let btn = self.gtk_builder
+            .get_object::<gtk::Button>("error_close_button")
+            .expect("Can't find error_close_button in ui file.");
+
+        let op = self.op.clone();
+        btn.connect_clicked(move |_| { op.lock().unwrap().hide_error(); });
     }
 
     fn connect_directory(&self) {
@@ -857,27 +1473,56 @@ impl App {
         q.connect_activate(move |_| { op.lock().unwrap().search_rooms(false); });
     }
 
-    fn create_load_more_btn(&self) {
+    fn create_timeline_status(&self) {
         let messages = self.gtk_builder
             .get_object::<gtk::ListBox>("message_list")
             .expect("Can't find message_list in ui file.");
 
-        let btn = self.op.lock().unwrap().load_more_btn.clone();
-        btn.show();
-        messages.add(&btn);
-
-        let op = self.op.clone();
-        btn.connect_clicked(move |_| { op.lock().unwrap().load_more_messages(); });
+        let label = self.op.lock().unwrap().timeline_status.clone();
+        label.set_margin_top(6);
+        label.set_margin_bottom(6);
+        if let Some(ctx) = label.get_style_context() {
+            ctx.add_class("dim-label");
+        }
+        label.show();
+        messages.add(&label);
     }
 
     fn connect_msg_scroll(&self) {
         let s = self.gtk_builder
             .get_object::<gtk::ScrolledWindow>("messages_scroll")
             .expect("Can't find message_scroll in ui file.");
+        let adj = s.get_vadjustment().expect("Can't get the messages_scroll adjustment.");
 
+        // older messages are loaded before reaching the top, the handlers
+        // use try_lock because set_value and the list changes can emit
+        // these signals while the op is locked
         let op = self.op.clone();
-        s.connect_edge_overshot(move |_, dir| if dir == gtk::PositionType::Top {
-            op.lock().unwrap().load_more_messages();
+        adj.connect_value_changed(move |adj| {
+            if adj.get_value() < adj.get_page_size() {
+                if let Ok(mut op) = op.try_lock() {
+                    op.load_more_messages();
+                }
+            }
+        });
+
+        let op = self.op.clone();
+        adj.connect_changed(move |adj| {
+            let bottom = match op.try_lock() {
+                Ok(mut op) => {
+                    // there's no scroll yet, so there's no way to reach the
+                    // top, we load until the view is filled
+                    if adj.get_upper() <= adj.get_page_size() {
+                        op.load_more_messages();
+                    }
+                    op.scroll_from_bottom.take()
+                }
+                Err(_) => None,
+            };
+
+            if let Some(b) = bottom {
+                adj.set_value(adj.get_upper() - b);
+            }
         });
     }
 
@@ -903,6 +1548,54 @@ impl App {
         });
     }
 
+    /// Files dropped on the message list and images pasted in the
+    /// msg_entry are sent as attachments
+    fn connect_attachments(&self) {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+        let scroll: gtk::ScrolledWindow = self.gtk_builder
+            .get_object("messages_scroll")
+            .expect("Couldn't find messages_scroll in ui file.");
+        let msg_entry: gtk::Entry = self.gtk_builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+
+        let targets = vec![gtk::TargetEntry::new("text/uri-list", gtk::TargetFlags::empty(), 0)];
+        scroll.drag_dest_set(gtk::DEST_DEFAULT_ALL, &targets, gdk::ACTION_COPY);
+
+        let op = self.op.clone();
+        let w = window.clone();
+        scroll.connect_drag_data_received(move |_, _, _, _, data, _, _| {
+            for uri in data.get_uris() {
+                let path = Url::parse(&uri)
+                    .ok()
+                    .and_then(|u| u.to_file_path().ok())
+                    .and_then(|p| p.to_str().map(String::from));
+
+                if let Some(path) = path {
+                    show_attach_dialog(&op, &w, path);
+                }
+            }
+        });
+
+        // the text paste does nothing if there's only an image in the
+        // clipboard, so the default handler can run too
+        let op = self.op.clone();
+        msg_entry.connect_paste_clipboard(move |_| {
+            let clipboard = gtk::Clipboard::get(&gdk::SELECTION_CLIPBOARD);
+            if !clipboard.wait_is_image_available() {
+                return;
+            }
+
+            let path = clipboard.wait_for_image()
+                .and_then(|pixbuf| op.lock().unwrap().save_pasted_image(&pixbuf));
+            if let Some(path) = path {
+                show_attach_dialog(&op, &window, path);
+            }
+        });
+    }
+
     fn connect_user_button(&self) {
         // Set up user popover
         let user_button: gtk::Button = self.gtk_builder
@@ -924,6 +1617,17 @@ impl App {
 
         let op = self.op.clone();
         login_btn.connect_clicked(move |_| op.lock().unwrap().login());
+
+        // Homeserver discovery from the user id
+        let user_entry: gtk::Entry = self.gtk_builder
+            .get_object("login_username")
+            .expect("Couldn't find login_username in ui file.");
+
+        let op = self.op.clone();
+        user_entry.connect_focus_out_event(move |_, _| {
+            op.lock().unwrap().discover_server();
+            Inhibit(false)
+        });
     }
 
     fn connect_register_button(&self) {
@@ -949,6 +1653,66 @@ impl App {
         });
     }
 
+    fn connect_settings(&self) {
+        let spin: gtk::SpinButton = self.gtk_builder
+            .get_object("cache_size_spin")
+            .expect("Couldn't find cache_size_spin in ui file.");
+        let size = self.op.lock().unwrap().settings.media_cache_size;
+        spin.set_value(size as f64);
+
+        let op = self.op.clone();
+        spin.connect_value_changed(move |spin| {
+            op.lock().unwrap().set_cache_size(spin.get_value_as_int() as u64);
+        });
+
+        let autoplay: gtk::CheckButton = self.gtk_builder
+            .get_object("autoplay_check")
+            .expect("Couldn't find autoplay_check in ui file.");
+        autoplay.set_active(self.op.lock().unwrap().settings.autoplay_animations);
+
+        let op = self.op.clone();
+        autoplay.connect_toggled(move |btn| {
+            op.lock().unwrap().set_autoplay(btn.get_active());
+        });
+
+        let combo: gtk::ComboBoxText = self.gtk_builder
+            .get_object("autoload_combo")
+            .expect("Couldn't find autoload_combo in ui file.");
+        let id = match self.op.lock().unwrap().settings.media_autoload {
+            Autoload::Always => "always",
+            Autoload::Unmetered => "unmetered",
+            Autoload::Never => "never",
+        };
+        combo.set_active_id(id);
+
+        let op = self.op.clone();
+        combo.connect_changed(move |combo| {
+            let autoload = match combo.get_active_id().as_ref().map(|s| s.as_str()) {
+                Some("unmetered") => Autoload::Unmetered,
+                Some("never") => Autoload::Never,
+                _ => Autoload::Always,
+            };
+            op.lock().unwrap().set_autoload(autoload);
+        });
+
+        let spin: gtk::SpinButton = self.gtk_builder
+            .get_object("autoload_size_spin")
+            .expect("Couldn't find autoload_size_spin in ui file.");
+        spin.set_value(self.op.lock().unwrap().settings.autoload_max_size as f64);
+
+        let op = self.op.clone();
+        spin.connect_value_changed(move |spin| {
+            op.lock().unwrap().set_autoload_max_size(spin.get_value_as_int() as u64);
+        });
+
+        let btn: gtk::Button = self.gtk_builder
+            .get_object("clear_cache_button")
+            .expect("Couldn't find clear_cache_button in ui file.");
+
+        let op = self.op.clone();
+        btn.connect_clicked(move |_| op.lock().unwrap().clear_cache());
+    }
+
     fn connect_room_treeview(&self) {
         // room selection
         let treeview: gtk::TreeView = self.gtk_builder
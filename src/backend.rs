@@ -9,6 +9,11 @@ extern crate cairo;
 
 use self::serde_json::Value as JsonValue;
 
+use std::collections::HashMap;
+use std::mem;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use self::url::Url;
@@ -18,27 +23,96 @@ use std::sync::mpsc::RecvError;
 
 use util::*;
 use error::Error;
-
+use mainloop::MainSender;
+use outbox;
+use store::Store;
+use cache::Cache;
+use mediacache;
+use filter;
+use pool;
+use pool::Priority;
+
+use settings::Autoload;
 use types::Message;
 use types::Member;
 use types::Protocol;
 use types::Room;
 
 
+/// Seconds to keep the resolved sender names and avatars
+const USER_INFO_TTL: u64 = 30 * 60;
+/// Min interval between the progress updates of a download
+const DOWNLOAD_PROGRESS_MS: u64 = 100;
+/// Minimum time between two saves of the store, in seconds. The store
+/// keeps the sync token with the rooms, so if the last changes aren't
+/// saved the next session syncs them again.
+const STORE_SAVE_SECS: u64 = 30;
+/// Time to wait before uploading again a sync filter rejected by the
+/// server, in milliseconds
+const FILTER_RETRY_MS: u64 = 10 * 60 * 1000;
+
 pub struct BackendData {
     user_id: String,
     access_token: String,
     server_url: String,
     since: String,
-    msgid: i32,
-    msgs_batch_start: String,
-    msgs_batch_end: String,
+    timelines: HashMap<String, RoomTimeline>,
     rooms_since: String,
     join_to_room: String,
+    room_gen: u64,
+    /// Active room, set with room_gen
+    room_id: String,
+    /// Messages to send, in order
+    outbox: Vec<Message>,
+    /// true while an outbox message is being sent
+    outbox_busy: bool,
+    /// increased on each outbox change, see outbox::save
+    outbox_seq: u64,
+    /// Attachments that couldn't be uploaded because we're offline, with
+    /// their local file and compression. They're uploaded again after the
+    /// next successful sync.
+    uploads: Vec<(Message, String, bool)>,
+    /// Local copy of the synced rooms, see store::Store
+    store: Store,
+    /// true if the store changed since the last save
+    store_dirty: bool,
+    store_saved: Option<Instant>,
+    /// Failed uploads of the sync filter in a row and when to try again,
+    /// the syncs use the filter json meanwhile
+    filter_failures: u32,
+    filter_retry: Option<Instant>,
+    /// Resolved sender names and avatar files by room and user id
+    user_info: Cache<(String, String), (String, String)>,
+    /// Scale factor of the window, the avatars are downloaded at
+    /// AVATAR_SIZE * scale pixels
+    scale: i32,
+}
+
+/// Pagination state of a room timeline
+#[derive(Debug, Clone, Default)]
+pub struct RoomTimeline {
+    /// token to paginate backwards from, it's the sync prev_batch until
+    /// the room messages are loaded and the end of the last page after that
+    pub prev_batch: String,
+    /// there's nothing older, the room creation was reached
+    pub reached_start: bool,
+    /// first event of a stored timeline, to get the token if the
+    /// prev_batch is unknown
+    pub oldest_event: String,
+}
+
+/// State of a file download, see BKCommand::DownloadFile
+#[derive(Debug)]
+pub enum Download {
+    /// downloaded bytes and total size, if it's known
+    Progress(u64, Option<u64>),
+    Done(String),
+    Canceled,
+    Failed(Error),
 }
 
 pub struct Backend {
-    tx: Sender<BKResponse>,
+    tx: MainSender<BKResponse>,
     data: Arc<Mutex<BackendData>>,
 }
 
@@ -47,16 +121,29 @@ pub enum BKCommand {
     Login(String, String, String),
     Register(String, String, String),
     Guest(String),
+    DiscoverServer(String),
     GetUsername,
     GetAvatar,
     Sync,
     SyncForced,
-    GetRoomMessages(String),
-    GetRoomMessagesTo(String),
-    GetThumbAsync(String, Sender<String>),
-    GetUserInfoAsync(String, Sender<(String, String)>),
-    SendMsg(String, String),
-    SetRoom(String),
+    GetRoomMessages(String, u64),
+    GetRoomMessagesTo(String, u64),
+    GetRoomMemberList(String, u64),
+    GetThumbAsync(String, MainSender<String>),
+    GetMediaAsync(String, u64, MainSender<String>),
+    // the room generation is None for lookups that aren't shown in the
+    // active room, like the notifications
+    GetUserInfoAsync(String, Option<u64>, String, MainSender<(String, String)>),
+    GetRoomAvatar(String, u64),
+    SetScaleFactor(i32),
+    SetCacheSize(u64),
+    SetAutoload(Autoload),
+    DownloadFile(String, String, Arc<AtomicBool>, MainSender<Download>),
+    ClearCache,
+    SendMsg(Message),
+    AttachFile(Message, String, bool),
+    CancelMsg(String),
+    SetRoom(String, u64),
     ShutDown,
     DirectoryProtocols,
     DirectorySearch(String, String, bool),
@@ -67,53 +154,108 @@ pub enum BKCommand {
 #[derive(Debug)]
 pub enum BKResponse {
     Token(String, String),
+    ServerDiscovered(String, Vec<String>),
     Name(String),
     Avatar(String),
     Sync,
+    Reconnecting(u64),
     Rooms(Vec<Room>, Option<Room>),
-    RoomDetail(String, String),
-    RoomAvatar(String),
+    // room scoped responses carry the room id and the generation of
+    // the request, see BKCommand::SetRoom
+    RoomDetail(String, u64, String, String),
+    RoomAvatar(String, u64, String),
     RoomMessages(Vec<Message>),
-    RoomMessagesInit(Vec<Message>),
-    RoomMessagesTo(Vec<Message>),
-    RoomMembers(Vec<Member>),
-    SendMsg,
+    RoomMessagesInit(String, u64, Vec<Message>),
+    RoomMessagesTo(String, u64, Vec<Message>),
+    RoomTimelineStart(String, u64),
+    RoomMembers(String, u64, Vec<Member>),
+    RoomMemberList(String, u64, Vec<Member>),
+    SentMsg(String, String),
+    AttachmentUploaded(Message),
+    MsgQueued(String),
+    Outbox(Vec<Message>),
     DirectoryProtocols(Vec<Protocol>),
     DirectorySearch(Vec<Room>),
     JoinRoom,
     MarkedAsRead(String, String),
+    CacheCleared,
 
     //errors
     UserNameError(Error),
     AvatarError(Error),
     LoginError(Error),
     GuestLoginError(Error),
+    DiscoverServerError(Error),
     SyncError(Error),
-    RoomDetailError(Error),
-    RoomAvatarError(Error),
-    RoomMessagesError(Error),
-    RoomMembersError(Error),
-    SendMsgError(Error),
-    SetRoomError(Error),
+    RoomDetailError(String, u64, Error),
+    RoomAvatarError(String, u64, Error),
+    RoomMessagesError(String, u64, Error),
+    RoomMembersError(String, u64, Error),
+    SendMsgError(String, Error),
+    SetRoomError(String, u64, Error),
     CommandError(Error),
     DirectoryError(Error),
     JoinRoomError(Error),
     MarkAsReadError(Error),
+    ClearCacheError(Error),
+    OutboxError(Error),
+    StoreError(Error),
+    SyncFilterError(Error),
 }
 
 
+impl BKResponse {
+    /// Room and generation of the room scoped responses, they're dropped
+    /// if the room isn't the active one anymore. None for the responses
+    /// that aren't tied to the active room.
+    pub fn room_scope(&self) -> Option<(&str, u64)> {
+        match *self {
+            BKResponse::RoomDetail(ref r, gen, _, _) |
+            BKResponse::RoomAvatar(ref r, gen, _) |
+            BKResponse::RoomMessagesInit(ref r, gen, _) |
+            BKResponse::RoomMessagesTo(ref r, gen, _) |
+            BKResponse::RoomTimelineStart(ref r, gen) |
+            BKResponse::RoomMembers(ref r, gen, _) |
+            BKResponse::RoomMemberList(ref r, gen, _) |
+            BKResponse::RoomDetailError(ref r, gen, _) |
+            BKResponse::RoomAvatarError(ref r, gen, _) |
+            BKResponse::RoomMessagesError(ref r, gen, _) |
+            BKResponse::RoomMembersError(ref r, gen, _) |
+            BKResponse::SetRoomError(ref r, gen, _) => Some((r.as_str(), gen)),
+            _ => None,
+        }
+    }
+
+    /// False if it's a room scoped response of other room than @roomid
+    /// or of an older generation than @gen
+    pub fn is_for_room(&self, roomid: &str, gen: u64) -> bool {
+        self.room_scope().map_or(true, |(r, g)| r == roomid && g == gen)
+    }
+}
+
 impl Backend {
-    pub fn new(tx: Sender<BKResponse>) -> Backend {
+    pub fn new(tx: MainSender<BKResponse>) -> Backend {
         let data = BackendData {
             user_id: String::from("Guest"),
             access_token: String::from(""),
             server_url: String::from("https://matrix.org"),
             since: String::from(""),
-            msgid: 1,
-            msgs_batch_start: String::from(""),
-            msgs_batch_end: String::from(""),
+            timelines: HashMap::new(),
             rooms_since: String::from(""),
             join_to_room: String::from(""),
+            room_gen: 0,
+            room_id: String::new(),
+            outbox: vec![],
+            outbox_busy: false,
+            outbox_seq: 0,
+            uploads: vec![],
+            store: Store::default(),
+            store_dirty: false,
+            store_saved: None,
+            filter_failures: 0,
+            filter_retry: None,
+            user_info: Cache::new(Duration::from_secs(USER_INFO_TTL)),
+            scale: 1,
         };
         Backend {
             tx: tx,
@@ -137,6 +279,10 @@ impl Backend {
                 let r = self.guest(server);
                 bkerror!(r, tx, BKResponse::GuestLoginError);
             }
+            Ok(BKCommand::DiscoverServer(uid)) => {
+                let r = self.discover_server(uid);
+                bkerror!(r, tx, BKResponse::DiscoverServerError);
+            }
             Ok(BKCommand::GetUsername) => {
                 let r = self.get_username();
                 bkerror!(r, tx, BKResponse::UserNameError);
@@ -154,29 +300,68 @@ impl Backend {
                 let r = self.sync();
                 bkerror!(r, tx, BKResponse::SyncError);
             }
-            Ok(BKCommand::GetRoomMessages(room)) => {
-                let r = self.get_room_messages(room, false);
-                bkerror!(r, tx, BKResponse::RoomMessagesError);
+            Ok(BKCommand::GetRoomMessages(room, gen)) => {
+                let r = self.get_room_messages(room.clone(), gen, false);
+                bkerror!(r, tx, |e| BKResponse::RoomMessagesError(room, gen, e));
             }
-            Ok(BKCommand::GetRoomMessagesTo(room)) => {
-                let r = self.get_room_messages(room, true);
-                bkerror!(r, tx, BKResponse::RoomMessagesError);
+            Ok(BKCommand::GetRoomMessagesTo(room, gen)) => {
+                let r = self.get_room_messages(room.clone(), gen, true);
+                bkerror!(r, tx, |e| BKResponse::RoomMessagesError(room, gen, e));
             }
-            Ok(BKCommand::GetUserInfoAsync(sender, ctx)) => {
-                let r = self.get_user_info_async(&sender, ctx);
+            Ok(BKCommand::GetRoomMemberList(room, gen)) => {
+                let r = self.get_room_member_list(room.clone(), gen);
+                bkerror!(r, tx, |e| BKResponse::RoomMembersError(room, gen, e));
+            }
+            Ok(BKCommand::GetUserInfoAsync(room, gen, sender, ctx)) => {
+                let r = self.get_user_info_async(&room, gen, &sender, ctx);
                 bkerror!(r, tx, BKResponse::CommandError);
             }
+            Ok(BKCommand::GetRoomAvatar(room, gen)) => {
+                let r = self.get_room_avatar(room.clone(), gen);
+                bkerror!(r, tx, |e| BKResponse::RoomAvatarError(room, gen, e));
+            }
+            Ok(BKCommand::SetScaleFactor(scale)) => {
+                self.set_scale_factor(scale);
+            }
             Ok(BKCommand::GetThumbAsync(media, ctx)) => {
                 let r = self.get_thumb_async(media, ctx);
                 bkerror!(r, tx, BKResponse::CommandError);
             }
-            Ok(BKCommand::SendMsg(room, msg)) => {
-                let r = self.send_msg(room, msg);
-                bkerror!(r, tx, BKResponse::SendMsgError);
+            Ok(BKCommand::GetMediaAsync(media, gen, ctx)) => {
+                let r = self.get_media_async(media, gen, ctx);
+                bkerror!(r, tx, BKResponse::CommandError);
+            }
+            Ok(BKCommand::DownloadFile(url, dest, cancel, ctx)) => {
+                self.download_file(url, dest, cancel, ctx);
+            }
+            Ok(BKCommand::SetCacheSize(bytes)) => {
+                self.set_cache_size(bytes);
+            }
+            Ok(BKCommand::SetAutoload(autoload)) => {
+                self.set_autoload(autoload);
+            }
+            Ok(BKCommand::ClearCache) => {
+                self.clear_cache();
+            }
+            Ok(BKCommand::SendMsg(msg)) => {
+                let txn_id = msg.txn_id.clone();
+                if let Err(e) = self.send_msg(msg) {
+                    tx.send(BKResponse::SendMsgError(txn_id, e)).unwrap();
+                }
+            }
+            Ok(BKCommand::AttachFile(msg, path, compress)) => {
+                let txn_id = msg.txn_id.clone();
+                if let Err(e) = self.attach_file(msg, path, compress) {
+                    tx.send(BKResponse::SendMsgError(txn_id, e)).unwrap();
+                }
+            }
+            Ok(BKCommand::CancelMsg(txn_id)) => {
+                let r = self.cancel_msg(txn_id);
+                bkerror!(r, tx, BKResponse::CommandError);
             }
-            Ok(BKCommand::SetRoom(room)) => {
-                let r = self.set_room(room);
-                bkerror!(r, tx, BKResponse::SetRoomError);
+            Ok(BKCommand::SetRoom(room, gen)) => {
+                let r = self.set_room(room.clone(), gen);
+                bkerror!(r, tx, |e| BKResponse::SetRoomError(room, gen, e));
             }
             Ok(BKCommand::DirectoryProtocols) => {
                 let r = self.protocols();
@@ -228,30 +413,55 @@ impl Backend {
         apptx
     }
 
-    pub fn set_room(&self, roomid: String) -> Result<(), Error> {
-        self.get_room_detail(roomid.clone(), String::from("m.room.topic"))?;
-        self.get_room_avatar(roomid.clone())?;
-        self.get_room_members(roomid.clone())?;
+    /// Sets the active room, @gen is the generation of the room change.
+    ///
+    /// Pending room requests of older generations are dropped, so responses
+    /// of the previous room don't land in the new one.
+    pub fn set_room(&self, roomid: String, gen: u64) -> Result<(), Error> {
+        self.data.lock().unwrap().room_gen = gen;
+        self.data.lock().unwrap().room_id = roomid.clone();
+
+        self.get_room_detail(roomid.clone(), gen, String::from("m.room.topic"))?;
+        self.get_room_avatar(roomid.clone(), gen)?;
+        self.get_room_members(roomid.clone(), gen)?;
+        self.get_room_messages(roomid.clone(), gen, false)?;
 
         Ok(())
     }
 
+    fn is_current_room(data: &Arc<Mutex<BackendData>>, gen: u64) -> bool {
+        data.lock().unwrap().room_gen == gen
+    }
+
+    /// True if nobody can be waiting for the sender info of @roomid asked
+    /// from the room generation @gen, because the room isn't the active
+    /// one. If it's the active one again, newer requests can be waiting
+    /// for the same info.
+    fn user_info_unused(data: &Arc<Mutex<BackendData>>, roomid: &str, gen: Option<u64>) -> bool {
+        let d = data.lock().unwrap();
+        match gen {
+            Some(gen) => d.room_gen != gen && d.room_id != roomid,
+            None => false,
+        }
+    }
+
     pub fn guest(&self, server: String) -> Result<(), Error> {
         let s = server.clone();
-        let url = Url::parse(&s).unwrap().join("/_matrix/client/r0/register?kind=guest")?;
+        let url = client_url(&Url::parse(&s)?, &["register"], &[("kind", "guest")])?;
         self.data.lock().unwrap().server_url = s;
 
         let data = self.data.clone();
         let tx = self.tx.clone();
-        post!(&url,
+        post!(&url, "",
               |r: JsonValue| {
             let uid = String::from(r["user_id"].as_str().unwrap_or(""));
             let tk = String::from(r["access_token"].as_str().unwrap_or(""));
             data.lock().unwrap().user_id = uid.clone();
             data.lock().unwrap().access_token = tk.clone();
             data.lock().unwrap().since = String::from("");
-            data.lock().unwrap().msgs_batch_end = String::from("");
-            data.lock().unwrap().msgs_batch_start = String::from("");
+            data.lock().unwrap().timelines.clear();
+            data.lock().unwrap().outbox.clear();
+            data.lock().unwrap().uploads.clear();
             tx.send(BKResponse::Token(uid, tk)).unwrap();
         },
               |err| tx.send(BKResponse::GuestLoginError(err)).unwrap());
@@ -261,7 +471,7 @@ impl Backend {
 
     pub fn login(&self, user: String, password: String, server: String) -> Result<(), Error> {
         let s = server.clone();
-        let url = Url::parse(&s)?.join("/_matrix/client/r0/login")?;
+        let url = client_url(&Url::parse(&s)?, &["login"], &[])?;
         self.data.lock().unwrap().server_url = s;
 
         let attrs = json!({
@@ -272,7 +482,7 @@ impl Backend {
 
         let data = self.data.clone();
         let tx = self.tx.clone();
-        post!(&url, &attrs,
+        post!(&url, "", &attrs,
             |r: JsonValue| {
                 let uid = String::from(r["user_id"].as_str().unwrap_or(""));
                 let tk = String::from(r["access_token"].as_str().unwrap_or(""));
@@ -280,8 +490,9 @@ impl Backend {
                 data.lock().unwrap().user_id = uid.clone();
                 data.lock().unwrap().access_token = tk.clone();
                 data.lock().unwrap().since = String::from("");
-                data.lock().unwrap().msgs_batch_end = String::from("");
-                data.lock().unwrap().msgs_batch_start = String::from("");
+                data.lock().unwrap().timelines.clear();
+                data.lock().unwrap().outbox.clear();
+                data.lock().unwrap().uploads.clear();
                 tx.send(BKResponse::Token(uid, tk)).unwrap();
             },
             |err| { tx.send(BKResponse::LoginError(err)).unwrap() }
@@ -292,7 +503,7 @@ impl Backend {
 
     pub fn register(&self, user: String, password: String, server: String) -> Result<(), Error> {
         let s = server.clone();
-        let url = Url::parse(&s).unwrap().join("/_matrix/client/r0/register?kind=user")?;
+        let url = client_url(&Url::parse(&s)?, &["register"], &[("kind", "user")])?;
         self.data.lock().unwrap().server_url = s;
 
         let attrs = json!({
@@ -304,7 +515,7 @@ impl Backend {
 
         let data = self.data.clone();
         let tx = self.tx.clone();
-        post!(&url, &attrs,
+        post!(&url, "", &attrs,
             |r: JsonValue| {
                 println!("RESPONSE: {:#?}", r);
                 let uid = String::from(r["user_id"].as_str().unwrap_or(""));
@@ -313,8 +524,9 @@ impl Backend {
                 data.lock().unwrap().user_id = uid.clone();
                 data.lock().unwrap().access_token = tk.clone();
                 data.lock().unwrap().since = String::from("");
-                data.lock().unwrap().msgs_batch_end = String::from("");
-                data.lock().unwrap().msgs_batch_start = String::from("");
+                data.lock().unwrap().timelines.clear();
+                data.lock().unwrap().outbox.clear();
+                data.lock().unwrap().uploads.clear();
                 tx.send(BKResponse::Token(uid, tk)).unwrap();
             },
             |err| { tx.send(BKResponse::LoginError(err)).unwrap() }
@@ -323,14 +535,30 @@ impl Backend {
         Ok(())
     }
 
+    pub fn discover_server(&self, userid: String) -> Result<(), Error> {
+        let server_name = get_server_name(&userid).ok_or(Error::BackendError)?;
+
+        let tx = self.tx.clone();
+        pool::spawn(Priority::Normal, move || match discover_homeserver(&server_name) {
+            Ok((url, versions)) => {
+                tx.send(BKResponse::ServerDiscovered(url, versions)).unwrap();
+            }
+            Err(err) => {
+                tx.send(BKResponse::DiscoverServerError(err)).unwrap();
+            }
+        });
+
+        Ok(())
+    }
+
     pub fn get_username(&self) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
+        let tk = self.data.lock().unwrap().access_token.clone();
         let uid = self.data.lock().unwrap().user_id.clone();
-        let id = uid.clone() + "/";
-        let url = baseu.join("/_matrix/client/r0/profile/")?.join(&id)?.join("displayname")?;
+        let url = client_url(&baseu, &["profile", &uid, "displayname"], &[])?;
 
         let tx = self.tx.clone();
-        get!(&url,
+        get!(&url, &tk,
             |r: JsonValue| {
                 let name = String::from(r["displayname"].as_str().unwrap_or(&uid));
                 tx.send(BKResponse::Name(name)).unwrap();
@@ -345,8 +573,10 @@ impl Backend {
         let baseu = self.get_base_url()?;
         let userid = self.data.lock().unwrap().user_id.clone();
 
+        let size = self.avatar_size();
+
         let tx = self.tx.clone();
-        thread::spawn(move || match get_user_avatar(&baseu, &userid) {
+        pool::spawn(Priority::Low, move || match get_user_avatar(&baseu, &userid, size) {
             Ok((_, fname)) => {
                 tx.send(BKResponse::Avatar(fname)).unwrap();
             }
@@ -361,73 +591,249 @@ impl Backend {
     pub fn sync(&self) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let token = self.data.lock().unwrap().access_token.clone();
-        let since = self.data.lock().unwrap().since.clone();
+        let mut since = self.data.lock().unwrap().since.clone();
         let userid = self.data.lock().unwrap().user_id.clone();
 
-        let mut params: String;
-
-        if since.is_empty() {
-            params = format!("?full_state=false&timeout=30000&access_token={}", token);
-            params = params +
-                     "&filter={\"room\": {\"state\": {\"types\": [\"m.room.*\"],},\"timeline\": \
-                      {\"limit\":0},\"ephemeral\": {\"types\": []}},\"presence\": {\"types\": \
-                      []},\"event_format\": \"client\",\"event_fields\": [\"type\", \"content\", \
-                      \"sender\"]}";
-        } else {
-            params =
-                format!("?full_state=false&timeout=30000&access_token={}&since={}", token, since);
-        }
+        // the first sync of the session continues from the stored rooms,
+        // so they're shown right away
+        if since.is_empty() && self.data.lock().unwrap().store.user_id != userid {
+            // an old or broken store is ignored, the rooms come from a
+            // full initial sync that replaces it
+            let stored = Store::load(&userid).and_then(|st| {
+                get_rooms_from_json(st.as_sync(), &userid).ok().map(|rooms| (st, rooms))
+            });
+            if let Some((st, rooms)) = stored {
+                since = st.next_batch.clone();
+                self.data.lock().unwrap().store = st;
+                self.data.lock().unwrap().since = since.clone();
 
-        let url = baseu.join("/_matrix/client/r0/sync")?.join(&params)?;
+                Backend::load_outbox(&self.data, &self.tx);
+                Backend::send_rooms(&self.data, &self.tx, rooms);
+            }
+        }
 
+        // the long poll and its retries wait on their own thread, so they
+        // don't take a pool worker for minutes
         let tx = self.tx.clone();
         let data = self.data.clone();
-        get!(&url,
-            |r: JsonValue| {
-                let next_batch = String::from(r["next_batch"].as_str().unwrap_or(""));
-                if since.is_empty() {
-                    let rooms = get_rooms_from_json(r, &userid).unwrap();
-
-                    let mut def: Option<Room> = None;
-                    let jtr = data.lock().unwrap().join_to_room.clone();
-                    if !jtr.is_empty() {
-                        if let Some(r) = rooms.iter().find(|x| x.id == jtr) {
-                            def = Some(r.clone());
+        thread::spawn(move || {
+            let filter = Backend::sync_filter_id(&baseu, &token, &userid, &data, &tx);
+
+            let mut params = vec![
+                ("full_state", "false"),
+                ("timeout", "30000"),
+                ("filter", &filter[..]),
+            ];
+            if !since.is_empty() {
+                params.push(("since", &since[..]));
+            }
+
+            let url = match client_url(&baseu, &["sync"], &params) {
+                Ok(u) => u,
+                Err(err) => {
+                    tx.send(BKResponse::SyncError(err)).unwrap();
+                    return;
+                }
+            };
+
+            // the sync doesn't give up on transient errors, it retries
+            // forever and the UI shows that we're reconnecting
+            let attrs = json!(null);
+            let js = json_q_retry("get", &url, &token, &attrs, None, |_, delay| {
+                tx.send(BKResponse::Reconnecting(delay)).unwrap();
+            });
+
+            match js {
+                Ok(r) => {
+                    let next_batch = String::from(r["next_batch"].as_str().unwrap_or(""));
+
+                    let snapshot = {
+                        let mut d = data.lock().unwrap();
+                        if since.is_empty() {
+                            let old = mem::replace(&mut d.store, Store::new(&userid));
+                            if old.user_id == userid {
+                                d.store.filter_id = old.filter_id;
+                                d.store.filter_json = old.filter_json;
+                            }
+                            d.store_dirty = true;
+                            d.store_saved = None;
                         }
-                    }
+                        if d.store.update(&r) {
+                            d.store_dirty = true;
+                        }
+                        d.store.next_batch = next_batch.clone();
 
-                    tx.send(BKResponse::Rooms(rooms, def)).unwrap();
+                        // names can change and need disambiguation, so the
+                        // senders of the rooms with member events are
+                        // resolved again
+                        let changed = rooms_with_member_events(&r);
+                        d.user_info.retain(|k| !changed.contains(&k.0));
 
-                } else {
-                    match get_rooms_timeline_from_json(&baseu, r) {
-                        Ok(msgs) => tx.send(BKResponse::RoomMessages(msgs)).unwrap(),
-                        Err(err) => tx.send(BKResponse::RoomMessagesError(err)).unwrap(),
+                        Backend::store_snapshot(&mut d)
+                    };
+
+                    // it's written without the lock, the other commands
+                    // don't wait for the disk
+                    if let Some(st) = snapshot {
+                        if let Err(err) = st.save() {
+                            tx.send(BKResponse::StoreError(err)).unwrap();
+                        }
+                    }
+
+                    // the sync prev_batch is where the backfill starts for
+                    // the rooms that weren't loaded yet
+                    if let Some(join) = r["rooms"]["join"].as_object() {
+                        let mut d = data.lock().unwrap();
+                        for (k, room) in join {
+                            if let Some(prev) = room["timeline"]["prev_batch"].as_str() {
+                                d.timelines.entry(k.clone()).or_insert(RoomTimeline {
+                                    prev_batch: String::from(prev),
+                                    reached_start: false,
+                                    oldest_event: String::new(),
+                                });
+                            }
+                        }
+                    }
+                    if since.is_empty() {
+                        match get_rooms_from_json(r, &userid) {
+                            Ok(rooms) => {
+                                // the queued messages are shown before loading the room
+                                Backend::load_outbox(&data, &tx);
+                                Backend::send_rooms(&data, &tx, rooms);
+                            }
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        }
+                    } else {
+                        match get_rooms_timeline_from_json(r) {
+                            Ok(msgs) => tx.send(BKResponse::RoomMessages(msgs)).unwrap(),
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        }
+                        // TODO: treat all events
+                        //println!("sync: {:#?}", r);
                     }
-                    // TODO: treat all events
-                    //println!("sync: {:#?}", r);
-                }
 
-                data.lock().unwrap().since = next_batch;
+                    data.lock().unwrap().since = next_batch;
 
-                tx.send(BKResponse::Sync).unwrap();
-            },
-            |err| { tx.send(BKResponse::SyncError(err)).unwrap() }
-        );
+                    tx.send(BKResponse::Sync).unwrap();
+
+                    // the connection is back, sending the queued messages
+                    Backend::flush_uploads(data.clone(), tx.clone());
+                    Backend::flush_outbox(data.clone(), tx.clone());
+                }
+                Err(err) => {
+                    tx.send(BKResponse::SyncError(err)).unwrap();
+                }
+            }
+        });
 
         Ok(())
     }
 
-    pub fn get_room_detail(&self, roomid: String, key: String) -> Result<(), Error> {
+    /// Copy of the store to save it, None if it didn't change or it was
+    /// saved less than STORE_SAVE_SECS ago
+    fn store_snapshot(data: &mut BackendData) -> Option<Store> {
+        let due = data.store_saved
+            .map_or(true, |t| t.elapsed() >= Duration::from_secs(STORE_SAVE_SECS));
+        if !data.store_dirty || !due {
+            return None;
+        }
+
+        data.store_dirty = false;
+        data.store_saved = Some(Instant::now());
+        Some(data.store.clone())
+    }
+
+    /// Returns the id of the uploaded sync filter, the filter is uploaded
+    /// once and the id is kept in the store.
+    ///
+    /// If the upload fails the filter json is returned, it can be used
+    /// directly in the sync.
+    fn sync_filter_id(baseu: &Url,
+                      tk: &str,
+                      userid: &str,
+                      data: &Arc<Mutex<BackendData>>,
+                      tx: &MainSender<BKResponse>)
+                      -> String {
+        let fjs = filter::sync_filter().to_json();
+
+        {
+            let d = data.lock().unwrap();
+            if d.store.user_id == userid && d.store.filter_json == fjs &&
+               !d.store.filter_id.is_empty() {
+                return d.store.filter_id.clone();
+            }
+            if d.filter_retry.map_or(false, |t| Instant::now() < t) {
+                return fjs;
+            }
+        }
+
+        match upload_filter(baseu, tk, userid, &fjs) {
+            Ok(id) => {
+                // on the first login the store is still empty, it's
+                // created here so the initial sync keeps the filter and
+                // it isn't uploaded again
+                let mut d = data.lock().unwrap();
+                if d.store.user_id != userid {
+                    d.store = Store::new(userid);
+                }
+                d.store.filter_id = id.clone();
+                d.store.filter_json = fjs;
+                d.filter_failures = 0;
+                d.filter_retry = None;
+                id
+            }
+            Err(err) => {
+                // the sync works without it, the error is only shown once
+                let mut d = data.lock().unwrap();
+                let delay = retry_delay(&err, d.filter_failures).unwrap_or(FILTER_RETRY_MS);
+                d.filter_retry = Some(Instant::now() + Duration::from_millis(delay));
+                d.filter_failures += 1;
+                if d.filter_failures == 1 {
+                    tx.send(BKResponse::SyncFilterError(err)).unwrap();
+                }
+                fjs
+            }
+        }
+    }
+
+    /// Sends the @rooms of the initial sync response, or the stored ones
+    fn send_rooms(data: &Arc<Mutex<BackendData>>, tx: &MainSender<BKResponse>, rooms: Vec<Room>) {
+        let mut def: Option<Room> = None;
+        let jtr = data.lock().unwrap().join_to_room.clone();
+        if !jtr.is_empty() {
+            if let Some(r) = rooms.iter().find(|x| x.id == jtr) {
+                def = Some(r.clone());
+            }
+        }
+
+        tx.send(BKResponse::Rooms(rooms, def)).unwrap();
+    }
+
+    pub fn get_room_detail(&self, roomid: String, gen: u64, key: String) -> Result<(), Error> {
+        // the stored state is kept up to date by the sync
+        let stored = self.data.lock().unwrap().store.rooms.get(&roomid).map(|r| {
+            let k = key.split('.').last().unwrap();
+            let value = r.state_content(&key).and_then(|c| c[k].as_str());
+            String::from(value.unwrap_or(""))
+        });
+        if let Some(value) = stored {
+            self.tx.send(BKResponse::RoomDetail(roomid, gen, key, value)).unwrap();
+            return Ok(());
+        }
+
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
-        let mut url = baseu.join("/_matrix/client/r0/rooms/")?.join(&(roomid + "/"))?;
-        url = url.join(&format!("state/{}", key))?;
-        url = url.join(&format!("?access_token={}", tk))?;
+        let url = client_url(&baseu, &["rooms", &roomid, "state", &key], &[])?;
 
         let tx = self.tx.clone();
+        let data = self.data.clone();
         let keys = key.clone();
-        get!(&url,
+        get!(&url, &tk,
             |r: JsonValue| {
+                if !Backend::is_current_room(&data, gen) {
+                    return;
+                }
+
                 let mut value = String::from("");
                 let k = keys.split('.').last().unwrap();
 
@@ -435,68 +841,129 @@ impl Backend {
                     Some(x) => { value = String::from(x); },
                     None => {}
                 }
-                tx.send(BKResponse::RoomDetail(key, value)).unwrap();
+                tx.send(BKResponse::RoomDetail(roomid, gen, key, value)).unwrap();
             },
-            |err| { tx.send(BKResponse::RoomDetailError(err)).unwrap() }
+            |err| { tx.send(BKResponse::RoomDetailError(roomid, gen, err)).unwrap() }
         );
 
         Ok(())
     }
 
-    pub fn get_room_avatar(&self, roomid: String) -> Result<(), Error> {
+    pub fn get_room_avatar(&self, roomid: String, gen: u64) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
         let userid = self.data.lock().unwrap().user_id.clone();
-        let roomu = baseu.join("/_matrix/client/r0/rooms/")?.join(&(roomid.clone() + "/"))?;
-        let mut url = roomu.join("state/m.room.avatar")?;
-        url = url.join(&format!("?access_token={}", tk))?;
+        let size = self.avatar_size();
+        let url = client_url(&baseu,
+                             &["rooms", &roomid, "state", "m.room.avatar"],
+                             &[])?;
 
         let tx = self.tx.clone();
-        get!(&url,
+        let data = self.data.clone();
+        get!(&url, &tk,
             |r: JsonValue| {
+                if !Backend::is_current_room(&data, gen) {
+                    return;
+                }
+
                 let avatar;
 
                 match r["url"].as_str() {
                     Some(u) => {
-                        avatar = thumb!(&baseu, u).unwrap_or(String::from(""));
+                        avatar = thumb!(&baseu, u, size).unwrap_or(String::from(""));
                     },
                     None => {
-                        avatar = get_room_avatar(&baseu, &tk, &userid, &roomid)
+                        avatar = get_room_avatar(&baseu, &tk, &userid, &roomid, size)
                             .unwrap_or(String::from(""));
                     }
                 }
-                tx.send(BKResponse::RoomAvatar(avatar)).unwrap();
+                tx.send(BKResponse::RoomAvatar(roomid, gen, avatar)).unwrap();
             },
-            |err| { tx.send(BKResponse::RoomAvatarError(err)).unwrap() }
+            |err| { tx.send(BKResponse::RoomAvatarError(roomid, gen, err)).unwrap() }
         );
 
         Ok(())
     }
 
-    pub fn get_room_messages(&self, roomid: String, to: bool) -> Result<(), Error> {
+    pub fn get_room_messages(&self, roomid: String, gen: u64, to: bool) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
 
         let tx = self.tx.clone();
         let data = self.data.clone();
 
-        thread::spawn(move || {
+        pool::spawn(Priority::Normal, move || {
+            // the room changed while this was waiting in the queue
+            if !Backend::is_current_room(&data, gen) {
+                return;
+            }
+
+            // the stored timeline is shown without waiting for the network
+            if !to && Backend::stored_room_messages(&data, &tx, &roomid, gen) {
+                return;
+            }
+
+            // the first page is the latest messages, older pages are
+            // paginated from the room token
             let end = match to {
-                true => Some(data.lock().unwrap().msgs_batch_end.clone()),
+                true => {
+                    let tl = data.lock().unwrap().timelines.get(&roomid).cloned();
+                    match tl {
+                        Some(ref t) if t.reached_start => {
+                            tx.send(BKResponse::RoomTimelineStart(roomid, gen)).unwrap();
+                            return;
+                        }
+                        // older stored events were dropped, the token is
+                        // asked to the server
+                        Some(ref t) if t.prev_batch.is_empty() && !t.oldest_event.is_empty() => {
+                            match get_event_token(&baseu, &tk, &roomid, &t.oldest_event) {
+                                Ok(token) => Some(token),
+                                Err(err) => {
+                                    tx.send(BKResponse::RoomMessagesError(roomid, gen, err)).unwrap();
+                                    return;
+                                }
+                            }
+                        }
+                        Some(t) => Some(t.prev_batch),
+                        None => None,
+                    }
+                }
                 false => None,
             };
-            match get_initial_room_messages(&baseu, tk, roomid, 10 as usize, 10, end) {
-                Ok((ms, start, end)) => {
-                    data.lock().unwrap().msgs_batch_start = start;
-                    data.lock().unwrap().msgs_batch_end = end;
-
-                    match to {
-                        false => tx.send(BKResponse::RoomMessagesInit(ms)).unwrap(),
-                        true => tx.send(BKResponse::RoomMessagesTo(ms)).unwrap(),
+            match get_initial_room_messages(&baseu, tk, roomid.clone(), 10 as usize, 10, end) {
+                Ok(_) if !Backend::is_current_room(&data, gen) => {}
+                Ok(page) => {
+                    let reached_start = page.reached_start;
+                    data.lock().unwrap().timelines.insert(roomid.clone(), RoomTimeline {
+                        prev_batch: page.prev_batch,
+                        reached_start: reached_start,
+                        oldest_event: String::new(),
+                    });
+
+                    // the members of the senders go first, so the messages
+                    // are shown with their names
+                    if !page.members.is_empty() {
+                        if let Some(r) = data.lock().unwrap().store.rooms.get_mut(&roomid) {
+                            for ev in page.members.iter() {
+                                r.add_missing_state(ev);
+                            }
+                        }
+                        let ms = page.members.iter().map(parse_member).collect();
+                        tx.send(BKResponse::RoomMembers(roomid.clone(), gen, ms)).unwrap();
+                    }
+
+                    let resp = match to {
+                        false => BKResponse::RoomMessagesInit(roomid.clone(), gen, page.msgs),
+                        true => BKResponse::RoomMessagesTo(roomid.clone(), gen, page.msgs),
                     };
+                    tx.send(resp).unwrap();
+
+                    if reached_start {
+                        tx.send(BKResponse::RoomTimelineStart(roomid, gen)).unwrap();
+                    }
                 }
                 Err(err) => {
-                    tx.send(BKResponse::RoomMessagesError(err)).unwrap();
+                    tx.send(BKResponse::RoomMessagesError(roomid, gen, err)).unwrap();
                 }
             }
         });
@@ -504,38 +971,91 @@ impl Backend {
         Ok(())
     }
 
-    pub fn get_room_members(&self, roomid: String) -> Result<(), Error> {
+    /// Sends the stored timeline of the room as the first messages,
+    /// returns false if there's nothing stored
+    fn stored_room_messages(data: &Arc<Mutex<BackendData>>,
+                            tx: &MainSender<BKResponse>,
+                            roomid: &str,
+                            gen: u64)
+                            -> bool {
+        let room = match data.lock().unwrap().store.rooms.get(roomid) {
+            Some(r) if !r.timeline.is_empty() => r.clone(),
+            _ => return false,
+        };
+
+        let ms = room.timeline
+            .iter()
+            .filter(|x| x["type"] == "m.room.message")
+            .map(|x| parse_room_message(String::from(roomid), x))
+            .collect();
+        let reached_start = room.timeline.iter().any(|x| x["type"] == "m.room.create");
+
+        data.lock().unwrap().timelines.insert(String::from(roomid), RoomTimeline {
+            prev_batch: room.prev_batch.clone(),
+            reached_start: reached_start,
+            oldest_event: String::from(room.timeline[0]["event_id"].as_str().unwrap_or("")),
+        });
+
+        tx.send(BKResponse::RoomMessagesInit(String::from(roomid), gen, ms)).unwrap();
+        if reached_start {
+            tx.send(BKResponse::RoomTimelineStart(String::from(roomid), gen)).unwrap();
+        }
+
+        true
+    }
+
+    /// Sends the known members of the room. The sync lazy loads the
+    /// members, so there are only the senders of the synced events, the
+    /// full list is fetched with get_room_member_list.
+    pub fn get_room_members(&self, roomid: String, gen: u64) -> Result<(), Error> {
+        let stored = self.data.lock().unwrap().store.rooms.get(&roomid).and_then(|r| r.members());
+        let ms = stored.unwrap_or(vec![]);
+        self.tx.send(BKResponse::RoomMembers(roomid, gen, ms)).unwrap();
+
+        Ok(())
+    }
+
+    /// Fetches the joined members of the room at the current sync point
+    pub fn get_room_member_list(&self, roomid: String, gen: u64) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
-        let mut url =
-            baseu.join("/_matrix/client/r0/rooms/")?.join(&(roomid + "/"))?.join("members")?;
-        url = url.join(&format!("?access_token={}", tk))?;
+        let since = self.data.lock().unwrap().since.clone();
+
+        let mut params = vec![("membership", "join")];
+        if !since.is_empty() {
+            params.push(("at", &since[..]));
+        }
+        let url = client_url(&baseu, &["rooms", &roomid, "members"], &params)?;
 
         let tx = self.tx.clone();
-        get!(&url,
+        let data = self.data.clone();
+        get!(&url, &tk,
             |r: JsonValue| {
-                //println!("{:#?}", r);
-                let mut ms: Vec<Member> = vec![];
-                for member in r["chunk"].as_array().unwrap().iter().rev() {
-                    if member["type"].as_str().unwrap() != "m.room.member" {
-                        continue;
-                    }
+                if !Backend::is_current_room(&data, gen) {
+                    return;
+                }
 
-                    let content = &member["content"];
-                    if content["membership"].as_str().unwrap() != "join" {
-                        continue;
+                let empty = vec![];
+                let evs: Vec<&JsonValue> = r["chunk"].as_array().unwrap_or(&empty)
+                    .iter()
+                    .filter(|x| x["type"] == "m.room.member")
+                    .collect();
+
+                // it's the state at our sync point, so the stored one is
+                // updated
+                if let Some(room) = data.lock().unwrap().store.rooms.get_mut(&roomid) {
+                    for ev in evs.iter() {
+                        room.set_state(ev);
                     }
-
-                    let m = Member {
-                        alias: String::from(content["displayname"].as_str().unwrap_or("")),
-                        uid: String::from(member["sender"].as_str().unwrap()),
-                        avatar: String::from(content["avatar_url"].as_str().unwrap_or("")),
-                    };
-                    ms.push(m);
                 }
-                tx.send(BKResponse::RoomMembers(ms)).unwrap();
+
+                let ms = evs.iter()
+                    .filter(|x| x["content"]["membership"] == "join")
+                    .map(|x| parse_member(x))
+                    .collect();
+                tx.send(BKResponse::RoomMemberList(roomid, gen, ms)).unwrap();
             },
-            |err| { tx.send(BKResponse::RoomMembersError(err)).unwrap() }
+            |err| { tx.send(BKResponse::RoomMembersError(roomid, gen, err)).unwrap() }
         );
 
         Ok(())
@@ -547,20 +1067,52 @@ impl Backend {
         Ok(url)
     }
 
+    /// Sends the name and the avatar file of the sender @uid in the room
+    /// @roomid, from the room member state. The global profile is only
+    /// asked for unknown senders.
     pub fn get_user_info_async(&self,
+                               roomid: &str,
+                               gen: Option<u64>,
                                uid: &str,
-                               tx: Sender<(String, String)>)
+                               tx: MainSender<(String, String)>)
                                -> Result<(), Error> {
         let baseu = self.get_base_url()?;
+        let size = self.avatar_size();
 
-        let u = String::from(uid);
-        thread::spawn(move || {
-            match get_user_avatar(&baseu, &u) {
+        if gen.map_or(false, |gen| !Backend::is_current_room(&self.data, gen)) {
+            return Ok(());
+        }
+
+        // the requests for the same sender wait for the first one
+        let key = (String::from(roomid), String::from(uid));
+        if !self.data.lock().unwrap().user_info.wait(key.clone(), tx) {
+            return Ok(());
+        }
+
+        let data = self.data.clone();
+        pool::spawn(Priority::Low, move || {
+            // the room changed while this was waiting in the queue, the
+            // other requests for the sender are of previous visits too
+            if Backend::user_info_unused(&data, &key.0, gen) {
+                let empty = (String::new(), String::new());
+                data.lock().unwrap().user_info.resolve(key, empty, false);
+                return;
+            }
+
+            let (ref r, ref u) = key;
+            let member = data.lock().unwrap().store.rooms.get(r).and_then(|x| x.member_info(u));
+            let info = match member {
+                Some((name, avatar)) => get_member_avatar(&baseu, u, name, &avatar, size),
+                None => get_user_avatar(&baseu, u, size),
+            };
+
+            match info {
                 Ok(info) => {
-                    tx.send(info).unwrap();
+                    data.lock().unwrap().user_info.resolve(key, info, true);
                 }
                 Err(_) => {
-                    tx.send((String::new(), String::new())).unwrap();
+                    let empty = (String::new(), String::new());
+                    data.lock().unwrap().user_info.resolve(key, empty, false);
                 }
             };
         });
@@ -568,11 +1120,35 @@ impl Backend {
         Ok(())
     }
 
-    pub fn get_thumb_async(&self, media: String, tx: Sender<String>) -> Result<(), Error> {
+    /// Size of the avatar files for the current scale factor
+    fn avatar_size(&self) -> i32 {
+        AVATAR_SIZE * self.data.lock().unwrap().scale
+    }
+
+    /// Sets the @scale factor of the window. The resolved user avatars
+    /// are dropped if it changes, so they're downloaded again at the
+    /// new size.
+    pub fn set_scale_factor(&self, scale: i32) {
+        let mut d = self.data.lock().unwrap();
+        if d.scale != scale {
+            d.scale = scale;
+            d.user_info.retain(|_| false);
+        }
+    }
+
+    /// Sets when the avatar thumbnails are downloaded, the cached sender
+    /// avatars are dropped so they're loaded again with the new policy.
+    pub fn set_autoload(&self, autoload: Autoload) {
+        set_avatar_autoload(autoload);
+        self.data.lock().unwrap().user_info.retain(|_| false);
+    }
+
+    pub fn get_thumb_async(&self, media: String, tx: MainSender<String>) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
+        let size = self.avatar_size();
 
-        thread::spawn(move || {
-            match thumb!(&baseu, &media) {
+        pool::spawn(Priority::Low, move || {
+            match thumb!(&baseu, &media, size) {
                 Ok(fname) => {
                     tx.send(fname).unwrap();
                 }
@@ -585,48 +1161,305 @@ impl Backend {
         Ok(())
     }
 
-    pub fn send_msg(&self, roomid: String, msg: String) -> Result<(), Error> {
+    /// Downloads the full @media to the media cache, sending the file
+    /// name to @tx, or an empty string if it can't be downloaded
+    pub fn get_media_async(&self, media: String, gen: u64, tx: MainSender<String>) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
-        let tk = self.data.lock().unwrap().access_token.clone();
-        let msgid;
+        let data = self.data.clone();
 
-        {
-            let mut data = self.data.lock().unwrap();
-            data.msgid = data.msgid + 1;
-            msgid = data.msgid;
-        }
+        pool::spawn(Priority::Low, move || {
+            // the image was in a room that isn't shown anymore
+            if !Backend::is_current_room(&data, gen) {
+                return;
+            }
+
+            match media!(&baseu, &media) {
+                Ok(fname) => {
+                    tx.send(fname).unwrap();
+                }
+                Err(_) => {
+                    tx.send(String::from("")).unwrap();
+                }
+            };
+        });
 
-        let mut url = baseu.join("/_matrix/client/r0/rooms/")?;
-        url = url.join(&(roomid + "/"))?.join("send/m.room.message/")?;
-        url = url.join(&format!("{}", msgid))?;
-        url = url.join(&format!("?access_token={}", tk))?;
+        Ok(())
+    }
 
-        let attrs = json!({
-            "body": msg,
-            "msgtype": "m.text"
+    /// Downloads the media of the mxc @url to the file @dest, sending the
+    /// progress to @tx. The download stops when @cancel is set.
+    pub fn download_file(&self,
+                         url: String,
+                         dest: String,
+                         cancel: Arc<AtomicBool>,
+                         tx: MainSender<Download>) {
+        let baseu = self.get_base_url();
+
+        // a big file can take minutes, the pool workers are kept for the
+        // thumbnails and avatars
+        thread::spawn(move || {
+            let mut last = Instant::now();
+            let interval = Duration::from_millis(DOWNLOAD_PROGRESS_MS);
+
+            let r = baseu.and_then(|b| mxc_download_url(&b, &url)).and_then(|u| {
+                download_file(u.as_str(), Path::new(&dest), &cancel, |done, total| {
+                    // the main loop doesn't need an update for each chunk
+                    if last.elapsed() >= interval {
+                        last = Instant::now();
+                        let _ = tx.send(Download::Progress(done, total));
+                    }
+                })
+            });
+
+            let st = match r {
+                Ok(true) => Download::Done(dest),
+                Ok(false) => Download::Canceled,
+                Err(err) => Download::Failed(err),
+            };
+            let _ = tx.send(st);
         });
+    }
 
+    /// Sets the size limit of the media cache, in bytes
+    pub fn set_cache_size(&self, bytes: u64) {
+        pool::spawn(Priority::Low, move || {
+            mediacache::set_max_size(bytes);
+        });
+    }
+
+    pub fn clear_cache(&self) {
         let tx = self.tx.clone();
-        query!("put", &url, &attrs,
-            move |_| {
-                tx.send(BKResponse::SendMsg).unwrap();
-            },
-            |err| { tx.send(BKResponse::SendMsgError(err)).unwrap(); }
-        );
 
+        pool::spawn(Priority::Normal, move || {
+            match mediacache::clear() {
+                Ok(_) => tx.send(BKResponse::CacheCleared).unwrap(),
+                Err(err) => tx.send(BKResponse::ClearCacheError(err)).unwrap(),
+            };
+        });
+    }
+
+    /// Queues the local echo @msg in the outbox, the messages are sent in
+    /// order and the outbox is stored on disk so nothing is lost if the
+    /// app is closed while offline.
+    pub fn send_msg(&self, msg: Message) -> Result<(), Error> {
+        Backend::queue_msg(self.data.clone(), self.tx.clone(), msg);
+        Ok(())
+    }
+
+    fn queue_msg(data: Arc<Mutex<BackendData>>, tx: MainSender<BKResponse>, msg: Message) {
+        let snapshot = {
+            let mut d = data.lock().unwrap();
+            if !d.outbox.iter().any(|m| m.txn_id == msg.txn_id) {
+                d.outbox.push(msg);
+            }
+            Backend::outbox_snapshot(&mut d)
+        };
+        Backend::save_outbox(&tx, snapshot);
+
+        Backend::flush_outbox(data, tx);
+    }
+
+    /// Uploads the file @path and queues the message @msg with it in the
+    /// outbox. The UI gets the message with the mxc url and the file info
+    /// in AttachmentUploaded, so a retry doesn't upload it again.
+    pub fn attach_file(&self, msg: Message, path: String, compress: bool) -> Result<(), Error> {
+        let baseu = self.get_base_url()?;
+        let tk = self.data.lock().unwrap().access_token.clone();
+        Backend::upload_attachment(self.data.clone(), self.tx.clone(), baseu, tk, msg, path, compress);
         Ok(())
     }
 
+    /// Uploads the attachment of @msg, see attach_file. If we're offline
+    /// it waits in the uploads queue, and the message is shown as queued.
+    fn upload_attachment(data: Arc<Mutex<BackendData>>,
+                         tx: MainSender<BKResponse>,
+                         baseu: Url,
+                         tk: String,
+                         msg: Message,
+                         path: String,
+                         compress: bool) {
+        pool::spawn(Priority::Normal, move || {
+            let r = read_attachment(&path, compress).and_then(|a| {
+                let url = upload_media(&baseu, &tk, a.data, &a.mimetype, &a.name)?;
+                Ok((url, a.info))
+            });
+
+            match r {
+                Ok((url, info)) => {
+                    let mut msg = msg;
+                    msg.url = url;
+                    msg.info = info;
+                    tx.send(BKResponse::AttachmentUploaded(msg.clone())).unwrap();
+                    Backend::queue_msg(data, tx, msg);
+                }
+                Err(ref err) if retry_delay(err, 0).is_some() => {
+                    tx.send(BKResponse::MsgQueued(msg.txn_id.clone())).unwrap();
+                    let mut d = data.lock().unwrap();
+                    if !d.uploads.iter().any(|u| u.0.txn_id == msg.txn_id) {
+                        d.uploads.push((msg, path, compress));
+                    }
+                }
+                Err(err) => {
+                    tx.send(BKResponse::SendMsgError(msg.txn_id, err)).unwrap();
+                }
+            };
+        });
+    }
+
+    /// Uploads again the attachments that failed while offline
+    fn flush_uploads(data: Arc<Mutex<BackendData>>, tx: MainSender<BKResponse>) {
+        let (uploads, baseu, tk) = {
+            let mut d = data.lock().unwrap();
+            let baseu = match Url::parse(&d.server_url) {
+                Ok(u) => u,
+                Err(_) => return,
+            };
+            (mem::replace(&mut d.uploads, vec![]), baseu, d.access_token.clone())
+        };
+
+        for (msg, path, compress) in uploads {
+            Backend::upload_attachment(data.clone(), tx.clone(), baseu.clone(), tk.clone(),
+                                       msg, path, compress);
+        }
+    }
+
+    pub fn cancel_msg(&self, txn_id: String) -> Result<(), Error> {
+        let snapshot = {
+            let mut d = self.data.lock().unwrap();
+            d.outbox.retain(|m| m.txn_id != txn_id);
+            d.uploads.retain(|u| u.0.txn_id != txn_id);
+            Backend::outbox_snapshot(&mut d)
+        };
+        Backend::save_outbox(&self.tx, snapshot);
+
+        Ok(())
+    }
+
+    /// User id, messages and sequence number of the outbox, to store it
+    /// after releasing the data lock
+    fn outbox_snapshot(data: &mut BackendData) -> (String, Vec<Message>, u64) {
+        data.outbox_seq += 1;
+        (data.user_id.clone(), data.outbox.clone(), data.outbox_seq)
+    }
+
+    fn save_outbox(tx: &MainSender<BKResponse>, snapshot: (String, Vec<Message>, u64)) {
+        let (userid, msgs, seq) = snapshot;
+        if let Err(err) = outbox::save(&userid, &msgs, seq) {
+            tx.send(BKResponse::OutboxError(err)).unwrap();
+        }
+    }
+
+    /// Loads the stored outbox of the logged user, and tells the UI to
+    /// show the messages as queued
+    fn load_outbox(data: &Arc<Mutex<BackendData>>, tx: &MainSender<BKResponse>) {
+        let userid = data.lock().unwrap().user_id.clone();
+        let mut msgs = outbox::load(&userid).unwrap_or(vec![]);
+
+        let mut d = data.lock().unwrap();
+        for m in d.outbox.iter() {
+            if !msgs.iter().any(|x| x.txn_id == m.txn_id) {
+                msgs.push(m.clone());
+            }
+        }
+        d.outbox = msgs.clone();
+
+        if !msgs.is_empty() {
+            tx.send(BKResponse::Outbox(msgs)).unwrap();
+        }
+    }
+
+    /// Sends the first outbox message and the next ones after it, one by
+    /// one. On network errors the messages wait in the outbox until the
+    /// next successful sync.
+    ///
+    /// Answers with SentMsg(txn_id, event_id), MsgQueued(txn_id) or
+    /// SendMsgError(txn_id, err) for the errors that won't go away by
+    /// retrying.
+    fn flush_outbox(data: Arc<Mutex<BackendData>>, tx: MainSender<BKResponse>) {
+        let (msg, baseu, tk) = {
+            let mut d = data.lock().unwrap();
+            if d.outbox_busy || d.outbox.is_empty() {
+                return;
+            }
+            let baseu = match Url::parse(&d.server_url) {
+                Ok(u) => u,
+                Err(_) => return,
+            };
+            d.outbox_busy = true;
+            (d.outbox[0].clone(), baseu, d.access_token.clone())
+        };
+
+        let txn_id = msg.txn_id.clone();
+        let mut attrs = json!({
+            "body": msg.body,
+            "msgtype": msg.mtype,
+        });
+        if !msg.url.is_empty() {
+            attrs["url"] = json!(msg.url);
+        }
+        if !msg.info.is_null() {
+            attrs["info"] = msg.info.clone();
+        }
+
+        let url = client_url(&baseu, &["rooms", &msg.room, "send", "m.room.message", &txn_id], &[]);
+        match url {
+            Ok(url) => {
+                json_q_pool(Priority::High, "put", url, tk, attrs, MAX_RETRIES, move |r| {
+                    Backend::outbox_sent(data, tx, txn_id, r);
+                });
+            }
+            Err(err) => Backend::outbox_sent(data, tx, txn_id, Err(err)),
+        };
+    }
+
+    /// Handles the result @r of sending the first outbox message, and
+    /// sends the next one
+    fn outbox_sent(data: Arc<Mutex<BackendData>>,
+                   tx: MainSender<BKResponse>,
+                   txn_id: String,
+                   r: Result<JsonValue, Error>) {
+        let mut d = data.lock().unwrap();
+        d.outbox_busy = false;
+
+        match r {
+            Err(ref err) if retry_delay(err, 0).is_some() => {
+                // we're offline, everything waits for the sync
+                for m in d.outbox.iter() {
+                    tx.send(BKResponse::MsgQueued(m.txn_id.clone())).unwrap();
+                }
+            }
+            r => {
+                d.outbox.retain(|m| m.txn_id != txn_id);
+                let snapshot = Backend::outbox_snapshot(&mut d);
+                drop(d);
+                Backend::save_outbox(&tx, snapshot);
+
+                match r {
+                    Ok(js) => {
+                        let evid = String::from(js["event_id"].as_str().unwrap_or(""));
+                        tx.send(BKResponse::SentMsg(txn_id, evid)).unwrap();
+                    }
+                    Err(err) => {
+                        tx.send(BKResponse::SendMsgError(txn_id, err)).unwrap();
+                    }
+                };
+
+                Backend::flush_outbox(data, tx);
+            }
+        };
+    }
+
     pub fn protocols(&self) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
-        let mut url = baseu.join("/_matrix/client/unstable/thirdparty/protocols")?;
-        let params = format!("?access_token={}", tk);
-        url = url.join(&params)?;
+        let url = build_url(&baseu,
+                            &["_matrix", "client", "unstable"],
+                            &["thirdparty", "protocols"],
+                            &[])?;
 
         let tx = self.tx.clone();
         let s = self.data.lock().unwrap().server_url.clone();
-        get!(&url,
+        get!(&url, &tk,
             move |r: JsonValue| {
                 let mut protocols: Vec<Protocol> = vec![];
 
@@ -662,9 +1495,7 @@ impl Backend {
                        -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
-        let mut url = baseu.join("/_matrix/client/r0/publicRooms")?;
-        let params = format!("?access_token={}", tk);
-        url = url.join(&params)?;
+        let url = client_url(&baseu, &["publicRooms"], &[])?;
 
         let mut attrs = json!({"limit": 20});
 
@@ -685,7 +1516,7 @@ impl Backend {
 
         let tx = self.tx.clone();
         let data = self.data.clone();
-        post!(&url, &attrs,
+        post!(&url, &tk, &attrs,
             move |r: JsonValue| {
                 let next_branch = r["next_batch"].as_str().unwrap_or("");
                 data.lock().unwrap().rooms_since = String::from(next_branch);
@@ -716,12 +1547,11 @@ impl Backend {
     pub fn join_room(&self, roomid: String) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
-        let mut url = baseu.join("/_matrix/client/r0/rooms/")?.join(&(roomid.clone() + "/join"))?;
-        url = url.join(&format!("?access_token={}", tk))?;
+        let url = client_url(&baseu, &["rooms", &roomid, "join"], &[])?;
 
         let tx = self.tx.clone();
         let data = self.data.clone();
-        post!(&url,
+        post!(&url, &tk,
             move |_: JsonValue| {
                 data.lock().unwrap().join_to_room = roomid.clone();
                 tx.send(BKResponse::JoinRoom).unwrap();
@@ -735,14 +1565,14 @@ impl Backend {
     pub fn mark_as_read(&self, roomid: String, eventid: String) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
-        let mut url = baseu.join("/_matrix/client/r0/rooms/")?;
-        url = url.join(&format!("{}/receipt/m.read/{}", roomid, eventid))?;
-        url = url.join(&format!("?access_token={}", tk))?;
+        let url = client_url(&baseu,
+                             &["rooms", &roomid, "receipt", "m.read", &eventid],
+                             &[])?;
 
         let tx = self.tx.clone();
         let r = roomid.clone();
         let e = eventid.clone();
-        post!(&url,
+        post!(&url, &tk,
             move |_: JsonValue| { tx.send(BKResponse::MarkedAsRead(r, e)).unwrap(); },
             |err| { tx.send(BKResponse::MarkAsReadError(err)).unwrap(); }
         );
@@ -750,3 +1580,99 @@ impl Backend {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mainloop;
+
+    fn room_responses(room: &str, gen: u64) -> Vec<BKResponse> {
+        let r = String::from(room);
+        vec![
+            BKResponse::RoomDetail(r.clone(), gen, String::from("m.room.topic"), String::new()),
+            BKResponse::RoomAvatar(r.clone(), gen, String::new()),
+            BKResponse::RoomMessagesInit(r.clone(), gen, vec![]),
+            BKResponse::RoomMessagesTo(r.clone(), gen, vec![]),
+            BKResponse::RoomTimelineStart(r.clone(), gen),
+            BKResponse::RoomMembers(r.clone(), gen, vec![]),
+            BKResponse::RoomMemberList(r.clone(), gen, vec![]),
+            BKResponse::RoomDetailError(r.clone(), gen, Error::BackendError),
+            BKResponse::RoomAvatarError(r.clone(), gen, Error::BackendError),
+            BKResponse::RoomMessagesError(r.clone(), gen, Error::BackendError),
+            BKResponse::RoomMembersError(r.clone(), gen, Error::BackendError),
+            BKResponse::SetRoomError(r, gen, Error::BackendError),
+        ]
+    }
+
+    #[test]
+    fn room_responses_are_scoped() {
+        for resp in room_responses("!a:example.org", 3) {
+            assert_eq!(resp.room_scope(), Some(("!a:example.org", 3)));
+            assert!(resp.is_for_room("!a:example.org", 3));
+        }
+    }
+
+    #[test]
+    fn previous_room_responses_are_dropped() {
+        for resp in room_responses("!old:example.org", 3) {
+            // other room shown
+            assert!(!resp.is_for_room("!new:example.org", 4));
+            // the same room shown again after a switch
+            assert!(!resp.is_for_room("!old:example.org", 5));
+        }
+    }
+
+    #[test]
+    fn other_responses_are_kept() {
+        let resps = vec![
+            BKResponse::Sync,
+            BKResponse::RoomMessages(vec![]),
+            BKResponse::SentMsg(String::from("m1.0"), String::from("$ev")),
+            BKResponse::SyncError(Error::BackendError),
+            BKResponse::CommandError(Error::BackendError),
+        ];
+        for resp in resps {
+            assert_eq!(resp.room_scope(), None);
+            assert!(resp.is_for_room("!a:example.org", 1));
+        }
+    }
+
+    #[test]
+    fn stale_room_generation() {
+        let (tx, _rx) = mainloop::channel();
+        let backend = Backend::new(tx);
+        backend.data.lock().unwrap().room_gen = 2;
+
+        assert!(Backend::is_current_room(&backend.data, 2));
+        assert!(!Backend::is_current_room(&backend.data, 1));
+    }
+
+    #[test]
+    fn stale_user_info_lookup_is_dropped() {
+        let (tx, _rx) = mainloop::channel();
+        let backend = Backend::new(tx);
+        backend.data.lock().unwrap().room_gen = 2;
+
+        let (utx, _urx) = mainloop::channel();
+        backend.get_user_info_async("!a:example.org", Some(1), "@u:example.org", utx).unwrap();
+
+        // nobody is fetching it, the next lookup has to
+        let key = (String::from("!a:example.org"), String::from("@u:example.org"));
+        let (utx, _urx) = mainloop::channel();
+        assert!(backend.data.lock().unwrap().user_info.wait(key, utx));
+    }
+
+    #[test]
+    fn user_info_of_a_revisited_room() {
+        let (tx, _rx) = mainloop::channel();
+        let backend = Backend::new(tx);
+        backend.data.lock().unwrap().room_gen = 3;
+        backend.data.lock().unwrap().room_id = String::from("!a:example.org");
+
+        // a -> b -> a, the lookup of the first visit answers the last one
+        assert!(!Backend::user_info_unused(&backend.data, "!a:example.org", Some(1)));
+        assert!(!Backend::user_info_unused(&backend.data, "!a:example.org", Some(3)));
+        assert!(Backend::user_info_unused(&backend.data, "!b:example.org", Some(2)));
+        assert!(!Backend::user_info_unused(&backend.data, "!b:example.org", None));
+    }
+}
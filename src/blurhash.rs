@@ -0,0 +1,143 @@
+use std::f32::consts::PI;
+
+
+/// Alphabet of the base 83 numbers of the blurhash strings
+const CHARS: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn decode83(s: &[u8]) -> Option<u32> {
+    let mut v = 0;
+    for c in s {
+        let d = CHARS.iter().position(|x| x == c)?;
+        v = v * 83 + d as u32;
+    }
+    Some(v)
+}
+
+fn srgb_to_linear(v: u32) -> f32 {
+    let v = v as f32 / 255.0;
+    match v <= 0.04045 {
+        true => v / 12.92,
+        false => ((v + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn linear_to_srgb(v: f32) -> u8 {
+    let v = v.max(0.0).min(1.0);
+    let s = match v <= 0.0031308 {
+        true => v * 12.92,
+        false => 1.055 * v.powf(1.0 / 2.4) - 0.055,
+    };
+    (s * 255.0 + 0.5) as u8
+}
+
+fn sign_pow(v: f32, exp: f32) -> f32 {
+    v.abs().powf(exp).copysign(v)
+}
+
+/// Decodes the blurhash @hash to a @width x @height image, as RGB bytes
+/// without row padding. @punch scales the contrast of the colors.
+///
+/// Returns None if @hash isn't a valid blurhash.
+pub fn decode(hash: &str, width: usize, height: usize, punch: f32) -> Option<Vec<u8>> {
+    let hash = hash.as_bytes();
+    if hash.len() < 6 {
+        return None;
+    }
+
+    let size = decode83(&hash[0..1])?;
+    let nx = (size % 9 + 1) as usize;
+    let ny = (size / 9 + 1) as usize;
+    if hash.len() != 4 + 2 * nx * ny {
+        return None;
+    }
+
+    let quant = decode83(&hash[1..2])?;
+    let max = (quant + 1) as f32 / 166.0 * punch;
+
+    let mut colors: Vec<[f32; 3]> = Vec::with_capacity(nx * ny);
+
+    let dc = decode83(&hash[2..6])?;
+    colors.push([srgb_to_linear(dc >> 16), srgb_to_linear((dc >> 8) & 255), srgb_to_linear(dc & 255)]);
+
+    for i in 1..nx * ny {
+        let ac = decode83(&hash[4 + i * 2..6 + i * 2])?;
+        let q = |v: u32| sign_pow((v as f32 - 9.0) / 9.0, 2.0) * max;
+        colors.push([q(ac / (19 * 19)), q((ac / 19) % 19), q(ac % 19)]);
+    }
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let mut c = [0.0; 3];
+            for j in 0..ny {
+                for i in 0..nx {
+                    let basis = (PI * x as f32 * i as f32 / width as f32).cos() *
+                                (PI * y as f32 * j as f32 / height as f32).cos();
+                    let color = colors[i + j * nx];
+                    for k in 0..3 {
+                        c[k] += color[k] * basis;
+                    }
+                }
+            }
+            pixels.extend(c.iter().map(|v| linear_to_srgb(*v)));
+        }
+    }
+
+    Some(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_dc_only() {
+        // a single component is a flat image of the dc color
+        let pixels = decode("00OZZy", 2, 2, 1.0).unwrap();
+        assert_eq!(pixels, vec![213, 30, 120, 213, 30, 120, 213, 30, 120, 213, 30, 120]);
+    }
+
+    #[test]
+    fn decode_known_hash() {
+        let expected: [u8; 36] = [
+            135, 164, 177, 161, 173, 177, 181, 180, 171, 160, 172, 174,
+            124, 154, 169, 148, 148, 154, 164, 145, 134, 146, 152, 155,
+            124, 144, 154, 144, 134, 132, 163, 130, 104, 148, 140, 134,
+        ];
+        let pixels = decode("LEHV6nWB2yk8pyo0adR*.7kCMdnj", 4, 3, 1.0).unwrap();
+        assert_eq!(pixels.len(), 4 * 3 * 3);
+        for (p, e) in pixels.iter().zip(expected.iter()) {
+            // f32 rounding
+            assert!((*p as i32 - *e as i32).abs() <= 1, "{:?} != {:?}", pixels, &expected[..]);
+        }
+    }
+
+    #[test]
+    fn decode_wrong_length() {
+        assert_eq!(decode("LEHV6nWB2yk8pyo0adR*.7kCMdn", 4, 3, 1.0), None);
+        assert_eq!(decode("LEHV6nWB2yk8pyo0adR*.7kCMdnjj", 4, 3, 1.0), None);
+        assert_eq!(decode("00OZZy00", 4, 3, 1.0), None);
+    }
+
+    #[test]
+    fn decode_short() {
+        assert_eq!(decode("", 4, 3, 1.0), None);
+        assert_eq!(decode("00OZZ", 4, 3, 1.0), None);
+    }
+
+    #[test]
+    fn decode_bad_chars() {
+        assert_eq!(decode("00OZ Z", 4, 3, 1.0), None);
+        assert_eq!(decode("\"0OZZy", 4, 3, 1.0), None);
+        assert_eq!(decode("LEHV6nWB2yk8pyo0adR*.7kCMd/j", 4, 3, 1.0), None);
+    }
+
+    #[test]
+    fn decode_multibyte() {
+        // the lengths are in bytes, these don't panic slicing a char
+        assert_eq!(decode("00OZé", 4, 3, 1.0), None);
+        assert_eq!(decode("0éZZy", 4, 3, 1.0), None);
+        assert_eq!(decode("LEHV6nWB2yk8pyo0adR*.7kCMé", 4, 3, 1.0), None);
+        assert_eq!(decode("✨✨", 4, 3, 1.0), None);
+    }
+}
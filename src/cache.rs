@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use mainloop::MainSender;
+
+
+/// Cache for values fetched by the backend threads, the values expire
+/// after the @ttl.
+///
+/// The lookups are coalesced, while a value is being fetched the other
+/// requests for the same key wait for it instead of fetching it again.
+pub struct Cache<K, V> {
+    ttl: Duration,
+    entries: HashMap<K, (V, Instant)>,
+    waiting: HashMap<K, Vec<MainSender<V>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+    pub fn new(ttl: Duration) -> Cache<K, V> {
+        Cache {
+            ttl: ttl,
+            entries: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached value if it isn't expired
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let expired = match self.entries.get(key) {
+            Some(&(ref v, t)) if t.elapsed() < self.ttl => return Some(v.clone()),
+            Some(_) => true,
+            None => false,
+        };
+
+        if expired {
+            self.entries.remove(key);
+        }
+        None
+    }
+
+    /// Answers @tx with the cached value or when it's fetched. Returns
+    /// true if the caller has to fetch the value, that is, if the value
+    /// isn't cached and nobody else is fetching it.
+    pub fn wait(&mut self, key: K, tx: MainSender<V>) -> bool {
+        if let Some(v) = self.get(&key) {
+            tx.send(v).unwrap();
+            return false;
+        }
+
+        let w = self.waiting.entry(key).or_insert(vec![]);
+        w.push(tx);
+        w.len() == 1
+    }
+
+    /// Answers the requests waiting for @key with @v, and caches it
+    /// if @cache is true, so the errors aren't cached
+    pub fn resolve(&mut self, key: K, v: V, cache: bool) {
+        for tx in self.waiting.remove(&key).unwrap_or(vec![]) {
+            let _ = tx.send(v.clone());
+        }
+
+        if cache {
+            self.entries.insert(key, (v, Instant::now()));
+        }
+    }
+
+    /// Removes the cached values with keys not matching @f
+    pub fn retain<F: Fn(&K) -> bool>(&mut self, f: F) {
+        self.entries.retain(|k, _| f(k));
+    }
+}
@@ -1,14 +1,75 @@
 extern crate cairo;
+extern crate glib;
 extern crate url;
 extern crate regex;
 extern crate reqwest;
+extern crate serde_json;
 
 use std::io;
+use std::fmt;
+
+use self::serde_json::Value as JsonValue;
 
 #[derive(Debug)]
 pub enum Error {
     BackendError,
     ReqwestError(reqwest::Error),
+    MatrixError(MatrixError),
+}
+
+/// Error response of the homeserver
+///
+/// The matrix error code (`M_FORBIDDEN`, `M_UNKNOWN_TOKEN`,
+/// `M_LIMIT_EXCEEDED`, ...) and message are empty if the server didn't
+/// answer with a standard error body.
+#[derive(Debug, Clone)]
+pub struct MatrixError {
+    pub status: u16,
+    pub errcode: String,
+    pub error: String,
+    pub retry_after_ms: Option<u64>,
+}
+
+impl MatrixError {
+    pub fn new(status: u16, js: &JsonValue) -> MatrixError {
+        MatrixError {
+            status: status,
+            errcode: String::from(js["errcode"].as_str().unwrap_or("")),
+            error: String::from(js["error"].as_str().unwrap_or("")),
+            retry_after_ms: js["retry_after_ms"].as_u64(),
+        }
+    }
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match &self.errcode[..] {
+            "M_FORBIDDEN" => "Forbidden",
+            "M_UNKNOWN_TOKEN" => "Your session has expired, please login again",
+            "M_MISSING_TOKEN" => "You need to login",
+            "M_LIMIT_EXCEEDED" => "Too many requests, wait a moment",
+            "M_USER_IN_USE" => "The user name is already taken",
+            "M_NOT_FOUND" => "Not found",
+            _ => "",
+        };
+
+        match (desc, &self.error[..]) {
+            ("", "") => write!(f, "The server answered with error {}", self.status),
+            ("", e) => write!(f, "{}", e),
+            (d, "") => write!(f, "{}", d),
+            (d, e) => write!(f, "{}: {}", d, e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BackendError => write!(f, "Unexpected response from the server"),
+            Error::ReqwestError(ref err) => write!(f, "Network error: {}", err),
+            Error::MatrixError(ref err) => write!(f, "{}", err),
+        }
+    }
 }
 
 impl From<reqwest::Error> for Error {
@@ -19,6 +80,8 @@ impl From<reqwest::Error> for Error {
 
 derror!(url::ParseError, Error::BackendError);
 derror!(io::Error, Error::BackendError);
+derror!(serde_json::Error, Error::BackendError);
 derror!(regex::Error, Error::BackendError);
 derror!(cairo::Status, Error::BackendError);
 derror!(cairo::IoError, Error::BackendError);
+derror!(glib::Error, Error::BackendError);
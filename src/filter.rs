@@ -0,0 +1,115 @@
+extern crate serde_json;
+
+/// Sync filter, see the filtering section of the client-server API.
+///
+/// The None fields aren't sent, so the server uses its defaults.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_fields: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<EventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<EventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomFilter>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct EventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RoomEventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    /// only the members of the senders of the returned events are sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lazy_load_members: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_redundant_members: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RoomFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_leave: Option<bool>,
+}
+
+fn strings(v: &[&str]) -> Option<Vec<String>> {
+    Some(v.iter().map(|s| String::from(*s)).collect())
+}
+
+/// Filter used for all the syncs
+///
+/// We only need the room events, without presence, typing notifications
+/// or receipts, and the room members are lazy loaded.
+pub fn sync_filter() -> Filter {
+    let room_types = strings(&["m.room.*"]);
+
+    Filter {
+        event_format: Some(String::from("client")),
+        presence: Some(EventFilter { types: strings(&[]), ..Default::default() }),
+        room: Some(RoomFilter {
+            state: Some(RoomEventFilter {
+                types: room_types.clone(),
+                lazy_load_members: Some(true),
+                ..Default::default()
+            }),
+            timeline: Some(RoomEventFilter {
+                limit: Some(20),
+                types: room_types,
+                lazy_load_members: Some(true),
+                ..Default::default()
+            }),
+            ephemeral: Some(RoomEventFilter { types: strings(&[]), ..Default::default() }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Filter for the room messages, with the members of the senders
+pub fn messages_filter() -> RoomEventFilter {
+    RoomEventFilter { lazy_load_members: Some(true), ..Default::default() }
+}
+
+impl Filter {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or(String::from("{}"))
+    }
+}
+
+impl RoomEventFilter {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or(String::from("{}"))
+    }
+}
@@ -1,10 +1,24 @@
 #[macro_use]
 extern crate serde_json;
 #[macro_use]
+extern crate lazy_static;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
 mod util;
+mod mainloop;
+mod pool;
+mod cache;
+mod mediacache;
+mod settings;
+mod blurhash;
 mod widgets;
 mod error;
 mod types;
+mod outbox;
+mod store;
+mod filter;
 mod backend;
 mod app;
 
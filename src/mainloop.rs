@@ -0,0 +1,133 @@
+extern crate glib;
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender, SendError, TryRecvError};
+
+
+/// Handler of a channel, it's dead when all the senders are dropped
+struct Handler {
+    f: RefCell<Box<FnMut() -> bool>>,
+    alive: Cell<bool>,
+}
+
+thread_local!(
+    static HANDLERS: RefCell<Vec<Rc<Handler>>> = RefCell::new(vec![]);
+    /// number of dispatches running, they're nested if a handler runs a
+    /// main loop, like a dialog
+    static DEPTH: Cell<u32> = Cell::new(0);
+);
+
+/// true while there's a dispatch queued in the main loop
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Sending side of a main loop channel, it can be used from any thread.
+///
+/// Every send wakes up the GTK main loop that runs the handlers of all the
+/// channels with pending messages.
+pub struct MainSender<T> {
+    tx: Sender<T>,
+}
+
+/// Receiving side of a main loop channel, the messages are passed to the
+/// attached handler in the GTK main thread.
+pub struct MainReceiver<T> {
+    rx: Receiver<T>,
+}
+
+pub fn channel<T>() -> (MainSender<T>, MainReceiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    (MainSender { tx: tx }, MainReceiver { rx: rx })
+}
+
+impl<T> MainSender<T> {
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.tx.send(t)?;
+        wakeup();
+        Ok(())
+    }
+}
+
+impl<T> Clone for MainSender<T> {
+    fn clone(&self) -> MainSender<T> {
+        MainSender { tx: self.tx.clone() }
+    }
+}
+
+impl<T> fmt::Debug for MainSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MainSender")
+    }
+}
+
+impl<T: 'static> MainReceiver<T> {
+    /// Calls @cb in the main loop for each received message. This should be
+    /// called from the GTK main thread.
+    ///
+    /// The handler is removed when all the senders are dropped, so one shot
+    /// channels are cleaned up after the response.
+    pub fn attach<F: FnMut(T) + 'static>(self, mut cb: F) {
+        let rx = self.rx;
+        let handler = move || loop {
+            match rx.try_recv() {
+                Ok(t) => cb(t),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        };
+
+        let handler = Handler {
+            f: RefCell::new(Box::new(handler)),
+            alive: Cell::new(true),
+        };
+        HANDLERS.with(|h| h.borrow_mut().push(Rc::new(handler)));
+        // there could be messages sent before the attach
+        wakeup();
+    }
+}
+
+fn wakeup() {
+    if !PENDING.swap(true, Ordering::SeqCst) {
+        glib::idle_add(dispatch);
+    }
+}
+
+/// Drains all the pending messages of every channel
+fn dispatch() -> glib::Continue {
+    PENDING.store(false, Ordering::SeqCst);
+
+    DEPTH.with(|d| d.set(d.get() + 1));
+
+    // the handlers stay in the list while running, so a nested main loop
+    // dispatches them too. They're looked up by index without keeping the
+    // list borrowed because they can attach new channels.
+    let mut i = 0;
+    while let Some(handler) = HANDLERS.with(|h| h.borrow().get(i).cloned()) {
+        i += 1;
+
+        // it's busy if it's running the nested main loop, the running
+        // call drains its channel when it returns
+        let alive = match handler.f.try_borrow_mut() {
+            Ok(mut f) => (&mut *f)(),
+            Err(_) => continue,
+        };
+        if !alive {
+            handler.alive.set(false);
+        }
+    }
+
+    // the dead handlers are removed by the outermost dispatch, so the
+    // indexes don't change under the running ones
+    let depth = DEPTH.with(|d| {
+        d.set(d.get() - 1);
+        d.get()
+    });
+    if depth == 0 {
+        HANDLERS.with(|h| h.borrow_mut().retain(|x| x.alive.get()));
+    }
+
+    glib::Continue(false)
+}
@@ -0,0 +1,236 @@
+extern crate xdg;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use error::Error;
+
+
+/// Default size limit of the cache, in bytes, until the settings are read
+const DEFAULT_MAX_SIZE: u64 = 200 * 1024 * 1024;
+
+lazy_static! {
+    static ref INDEX: Mutex<Index> = Mutex::new(Index::new());
+}
+
+/// Counter for the temporary file names, so concurrent downloads of the
+/// same media don't write to the same file
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+struct Entry {
+    size: u64,
+    used: SystemTime,
+}
+
+/// Size and last use of the cached files, used to evict the least
+/// recently used ones when the cache grows over the size limit.
+///
+/// It's built from the cache dir the first time it's used, taking the
+/// modification time as the last use.
+struct Index {
+    loaded: bool,
+    max_size: u64,
+    total: u64,
+    files: HashMap<PathBuf, Entry>,
+}
+
+impl Index {
+    fn new() -> Index {
+        Index {
+            loaded: false,
+            max_size: DEFAULT_MAX_SIZE,
+            total: 0,
+            files: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+
+        let dir = match cache_dir() {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+
+        for server in dir_entries(&dir) {
+            for f in dir_entries(&server.path()) {
+                let path = f.path();
+                if is_tmp(&path) {
+                    // leftover of an interrupted write
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+
+                if let Ok(md) = f.metadata() {
+                    let used = md.modified().unwrap_or(SystemTime::now());
+                    self.insert(path, md.len(), used);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, size: u64, used: SystemTime) {
+        self.remove(&path);
+        self.total += size;
+        self.files.insert(path, Entry { size: size, used: used });
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(e) = self.files.remove(path) {
+            self.total -= e.size;
+        }
+    }
+
+    /// Removes the least recently used files until the cache fits in
+    /// the size limit
+    fn evict(&mut self) {
+        if self.total <= self.max_size {
+            return;
+        }
+
+        let mut lru: Vec<(PathBuf, SystemTime)> = self.files
+            .iter()
+            .map(|(k, e)| (k.clone(), e.used))
+            .collect();
+        lru.sort_by_key(|&(_, used)| used);
+
+        for (path, _) in lru {
+            if self.total <= self.max_size {
+                break;
+            }
+            let _ = fs::remove_file(&path);
+            self.remove(&path);
+        }
+    }
+}
+
+fn cache_dir() -> Result<PathBuf, Error> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+    Ok(xdg_dirs.create_cache_directory("media")?)
+}
+
+fn dir_entries(dir: &Path) -> Vec<fs::DirEntry> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+fn is_tmp(path: &Path) -> bool {
+    path.extension().map(|e| e == "tmp").unwrap_or(false)
+}
+
+/// Replaces the characters that can't be used in a file name, the
+/// server and media ids come from the mxc urls of other users
+fn safe_name(s: &str) -> String {
+    let name: String = s.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+
+    match name.starts_with('.') {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+/// Path of the cached file of the media @media of @server.
+///
+/// @thumb is the width, height and method of the thumbnail, or None for
+/// the full media, so the thumbnails of different sizes don't overwrite
+/// each other.
+pub fn media_path(server: &str, media: &str, thumb: Option<(i32, i32, &str)>) -> Result<PathBuf, Error> {
+    let dir = cache_dir()?.join(safe_name(server));
+    fs::create_dir_all(&dir)?;
+
+    let name = match thumb {
+        Some((w, h, method)) => format!("{}-{}x{}-{}", safe_name(media), w, h, safe_name(method)),
+        None => safe_name(media),
+    };
+
+    Ok(dir.join(name))
+}
+
+/// Returns true if @path is in the cache, and marks it as used
+pub fn hit(path: &Path) -> bool {
+    let mut index = INDEX.lock().unwrap();
+    index.load();
+
+    if !path.is_file() {
+        index.remove(path);
+        return false;
+    }
+
+    let known = match index.files.get_mut(path) {
+        Some(e) => {
+            e.used = SystemTime::now();
+            true
+        }
+        None => false,
+    };
+
+    if !known {
+        let size = fs::metadata(path).map(|md| md.len()).unwrap_or(0);
+        index.insert(path.to_path_buf(), size, SystemTime::now());
+    }
+
+    true
+}
+
+/// Writes @data to a temporary file next to @path that replaces it, so
+/// readers never find a half written file
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let n = TMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let name = path.file_name().and_then(|f| f.to_str()).unwrap_or("media");
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, n));
+
+    let r = File::create(&tmp).and_then(|mut f| f.write_all(data));
+    if let Err(e) = r {
+        let _ = fs::remove_file(&tmp);
+        return Err(Error::from(e));
+    }
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Stores @data in the cache file @path and evicts the least recently
+/// used files if the cache is over the size limit
+pub fn store(path: &Path, data: &[u8]) -> Result<(), Error> {
+    write_atomic(path, data)?;
+
+    let mut index = INDEX.lock().unwrap();
+    index.load();
+    index.insert(path.to_path_buf(), data.len() as u64, SystemTime::now());
+    index.evict();
+
+    Ok(())
+}
+
+/// Sets the size limit of the cache to @bytes, evicting files if the
+/// cache is bigger
+pub fn set_max_size(bytes: u64) {
+    let mut index = INDEX.lock().unwrap();
+    index.max_size = bytes;
+    index.load();
+    index.evict();
+}
+
+/// Removes all the cached media
+pub fn clear() -> Result<(), Error> {
+    let mut index = INDEX.lock().unwrap();
+    fs::remove_dir_all(cache_dir()?)?;
+    index.files.clear();
+    index.total = 0;
+    index.loaded = true;
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+extern crate xdg;
+extern crate serde_json;
+extern crate chrono;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::sync::Mutex;
+
+use self::chrono::prelude::*;
+use self::serde_json::Value as JsonValue;
+
+use error::Error;
+use types::Message;
+use util::ts_to_datetime;
+
+
+/// Messages composed offline are stored in this file, in the XDG data dir,
+/// until they're sent. It's a map from user id to the list of messages.
+const OUTBOX_FILE: &'static str = "outbox.json";
+
+lazy_static! {
+    /// Sequence number of the last saved outbox, the saves are done
+    /// without the backend lock so an older one can come late
+    static ref SAVED: Mutex<u64> = Mutex::new(0);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct OutboxMsg {
+    room: String,
+    sender: String,
+    mtype: String,
+    body: String,
+    txn_id: String,
+    /// mxc url and info of the uploaded attachments
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    info: JsonValue,
+    /// local time when the message was composed, in milliseconds
+    ts: i64,
+}
+
+impl<'a> From<&'a Message> for OutboxMsg {
+    fn from(msg: &'a Message) -> OutboxMsg {
+        OutboxMsg {
+            room: msg.room.clone(),
+            sender: msg.sender.clone(),
+            mtype: msg.mtype.clone(),
+            body: msg.body.clone(),
+            txn_id: msg.txn_id.clone(),
+            url: msg.url.clone(),
+            info: msg.info.clone(),
+            ts: msg.date.timestamp() * 1000 + msg.date.timestamp_subsec_millis() as i64,
+        }
+    }
+}
+
+impl From<OutboxMsg> for Message {
+    fn from(msg: OutboxMsg) -> Message {
+        Message {
+            sender: msg.sender,
+            mtype: msg.mtype,
+            body: msg.body,
+            date: ts_to_datetime(msg.ts),
+            room: msg.room,
+            thumb: String::new(),
+            url: msg.url,
+            info: msg.info,
+            event_id: String::new(),
+            txn_id: msg.txn_id,
+            unsigned: json!(null),
+        }
+    }
+}
+
+fn read_all() -> Result<HashMap<String, Vec<OutboxMsg>>, Error> {
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+    match xdg_dirs.find_data_file(OUTBOX_FILE) {
+        Some(path) => Ok(serde_json::from_reader(File::open(path)?)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Returns the messages of @userid waiting to be sent, in order
+pub fn load(userid: &str) -> Result<Vec<Message>, Error> {
+    let mut all = read_all()?;
+    let msgs = all.remove(userid).unwrap_or(vec![]);
+    Ok(msgs.into_iter().map(Message::from).collect())
+}
+
+/// Replaces the stored messages of @userid with @msgs, @seq is the
+/// sequence number of this outbox state, it isn't saved if a newer one
+/// was saved already.
+///
+/// The file has the messages of all the users, so it isn't written if it
+/// can't be read, and it's written to a temporary file that replaces it
+/// so a crash while saving doesn't lose the queued messages.
+pub fn save(userid: &str, msgs: &[Message], seq: u64) -> Result<(), Error> {
+    let mut saved = SAVED.lock().unwrap();
+    if seq < *saved {
+        return Ok(());
+    }
+
+    let mut all = read_all()?;
+    match msgs.is_empty() {
+        true => all.remove(userid),
+        false => all.insert(String::from(userid), msgs.iter().map(OutboxMsg::from).collect()),
+    };
+
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+    let path = xdg_dirs.place_data_file(OUTBOX_FILE)?;
+    let tmp = path.with_extension("json.tmp");
+
+    serde_json::to_writer(File::create(&tmp)?, &all)?;
+    fs::rename(tmp, path)?;
+    *saved = seq;
+
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// Number of threads running backend requests
+const WORKERS: usize = 6;
+/// Workers reserved for high priority jobs, so the message sending never
+/// waits behind a bunch of avatar downloads
+const HIGH_WORKERS: usize = 2;
+
+lazy_static! {
+    static ref POOL: WorkerPool = WorkerPool::new(WORKERS, HIGH_WORKERS);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Media and avatar downloads
+    Low,
+    Normal,
+    /// Message sending
+    High,
+}
+
+struct Job {
+    prio: Priority,
+    seq: u64,
+    f: Box<FnOnce() + Send>,
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Job) -> bool {
+        self.prio == other.prio && self.seq == other.seq
+    }
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Job) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Job {
+    // higher priority first, and older jobs first for the same priority
+    fn cmp(&self, other: &Job) -> Ordering {
+        self.prio.cmp(&other.prio).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct Queue {
+    jobs: BinaryHeap<Job>,
+    /// jobs waiting to be queued at the given time
+    delayed: Vec<(Instant, Job)>,
+    seq: u64,
+}
+
+impl Queue {
+    fn push(&mut self, prio: Priority, f: Box<FnOnce() + Send>, at: Option<Instant>) {
+        self.seq += 1;
+        let job = Job { prio: prio, seq: self.seq, f: f };
+        match at {
+            Some(t) => self.delayed.push((t, job)),
+            None => self.jobs.push(job),
+        };
+    }
+
+    /// Queues the delayed jobs that are due, returns the time until the
+    /// next one or None if there are no delayed jobs
+    fn promote(&mut self, now: Instant) -> Option<Duration> {
+        let (due, later): (Vec<(Instant, Job)>, Vec<(Instant, Job)>) = self.delayed
+            .drain(..)
+            .partition(|&(t, _)| t <= now);
+        self.delayed = later;
+        self.jobs.extend(due.into_iter().map(|(_, job)| job));
+
+        self.delayed.iter().map(|&(t, _)| t - now).min()
+    }
+}
+
+/// Fixed number of threads running jobs by priority
+pub struct WorkerPool {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+}
+
+impl WorkerPool {
+    /// Starts @workers threads, the first @high_workers of them only run
+    /// high priority jobs.
+    pub fn new(workers: usize, high_workers: usize) -> WorkerPool {
+        let queue = Queue {
+            jobs: BinaryHeap::new(),
+            delayed: vec![],
+            seq: 0,
+        };
+        let queue = Arc::new((Mutex::new(queue), Condvar::new()));
+
+        for i in 0..workers {
+            let q = queue.clone();
+            let min = match i < high_workers {
+                true => Priority::High,
+                false => Priority::Low,
+            };
+            thread::spawn(move || worker(q, min));
+        }
+
+        WorkerPool { queue: queue }
+    }
+
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, prio: Priority, f: F) {
+        self.push(prio, Box::new(f), None);
+    }
+
+    /// Runs @f after @delay, without taking a worker while waiting
+    pub fn spawn_after<F: FnOnce() + Send + 'static>(&self, prio: Priority, delay: Duration, f: F) {
+        self.push(prio, Box::new(f), Some(Instant::now() + delay));
+    }
+
+    fn push(&self, prio: Priority, f: Box<FnOnce() + Send>, at: Option<Instant>) {
+        let &(ref lock, ref cvar) = &*self.queue;
+        lock.lock().unwrap().push(prio, f, at);
+        cvar.notify_all();
+    }
+}
+
+fn worker(queue: Arc<(Mutex<Queue>, Condvar)>, min: Priority) {
+    let &(ref lock, ref cvar) = &*queue;
+
+    loop {
+        let job = {
+            let mut q = lock.lock().unwrap();
+            loop {
+                let queued = q.jobs.len();
+                let next = q.promote(Instant::now());
+                if q.jobs.len() > queued {
+                    // the workers that can run them could be waiting
+                    cvar.notify_all();
+                }
+                if q.jobs.peek().map_or(false, |j| j.prio >= min) {
+                    break q.jobs.pop().unwrap();
+                }
+                q = match next {
+                    Some(timeout) => cvar.wait_timeout(q, timeout).unwrap().0,
+                    None => cvar.wait(q).unwrap(),
+                };
+            }
+        };
+
+        // a panicking job shouldn't take the worker down with it
+        let f = job.f;
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(move || f()));
+    }
+}
+
+/// Runs @f in the shared backend worker pool
+pub fn spawn<F: FnOnce() + Send + 'static>(prio: Priority, f: F) {
+    POOL.spawn(prio, f);
+}
+
+/// Runs @f in the shared backend worker pool after @delay
+pub fn spawn_after<F: FnOnce() + Send + 'static>(prio: Priority, delay: Duration, f: F) {
+    POOL.spawn_after(prio, delay, f);
+}
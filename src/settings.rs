@@ -0,0 +1,95 @@
+extern crate xdg;
+extern crate serde_json;
+
+use std::fs;
+use std::fs::File;
+
+use error::Error;
+
+
+/// User settings, stored in the XDG config dir
+const SETTINGS_FILE: &'static str = "settings.json";
+
+/// When the message images and avatars are downloaded without asking
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Autoload {
+    Always,
+    /// only if the network connection isn't metered
+    Unmetered,
+    Never,
+}
+
+impl Autoload {
+    /// True if the media can be downloaded without asking, @metered is
+    /// true if the connection is metered
+    pub fn allows(&self, metered: bool) -> bool {
+        match *self {
+            Autoload::Always => true,
+            Autoload::Unmetered => !metered,
+            Autoload::Never => false,
+        }
+    }
+}
+
+/// The missing fields take the default value, so the settings of older
+/// versions can be read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// size limit of the media cache, in MB
+    pub media_cache_size: u64,
+    /// play the animated images all the time, if false they're only
+    /// played while the pointer is over them
+    pub autoplay_animations: bool,
+    /// when the images of the messages are downloaded without a click
+    pub media_autoload: Autoload,
+    /// images bigger than this, in MB, aren't loaded without asking, 0
+    /// for no limit
+    pub autoload_max_size: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            media_cache_size: 200,
+            autoplay_animations: true,
+            media_autoload: Autoload::Always,
+            autoload_max_size: 0,
+        }
+    }
+}
+
+impl Settings {
+    /// Returns the stored settings, or the default ones if there's
+    /// nothing stored or the file can't be read
+    pub fn load() -> Settings {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+        xdg_dirs.find_config_file(SETTINGS_FILE)
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+        let path = xdg_dirs.place_config_file(SETTINGS_FILE)?;
+        let tmp = path.with_extension("json.tmp");
+
+        serde_json::to_writer_pretty(File::create(&tmp)?, self)?;
+        fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Size limit of the media cache, in bytes
+    pub fn media_cache_bytes(&self) -> u64 {
+        self.media_cache_size * 1024 * 1024
+    }
+
+    /// True if an image of @size bytes, 0 if it's unknown, can be loaded
+    /// without asking, @metered is true if the connection is metered
+    pub fn can_autoload(&self, size: u64, metered: bool) -> bool {
+        self.media_autoload.allows(metered) && (self.autoload_max_size == 0 || size <= self.autoload_max_size * 1024 * 1024)
+    }
+}
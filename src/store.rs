@@ -0,0 +1,367 @@
+extern crate xdg;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::PathBuf;
+
+use self::serde_json::Value as JsonValue;
+
+use error::Error;
+use types::Member;
+use util::parse_member;
+
+
+/// Max number of timeline events stored for each room
+const MAX_TIMELINE_EVENTS: usize = 100;
+
+/// Local copy of the synced rooms, stored in the XDG data dir so the rooms
+/// are available at startup and the sync continues from the next_batch
+/// token instead of doing a full initial sync.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Store {
+    pub user_id: String,
+    pub next_batch: String,
+    pub rooms: HashMap<String, StoredRoom>,
+    /// Uploaded sync filter, it's uploaded again if the filter changes
+    #[serde(default)]
+    pub filter_id: String,
+    #[serde(default)]
+    pub filter_json: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StoredRoom {
+    /// Current state, the events by type and state key
+    pub state: HashMap<String, HashMap<String, JsonValue>>,
+    /// Latest timeline events, the oldest first
+    pub timeline: Vec<JsonValue>,
+    /// Token to paginate before the first timeline event, it's empty if
+    /// older events were dropped
+    pub prev_batch: String,
+    pub notifications: i64,
+    /// Heroes and member counts of the room, to name it without the lazy
+    /// loaded members
+    #[serde(default)]
+    pub summary: JsonValue,
+    /// Number of joined members with each display name, it's built from
+    /// the state when the store is loaded
+    #[serde(skip)]
+    names: HashMap<String, usize>,
+}
+
+/// Display name of the member event @ev if the member is joined
+fn joined_name(ev: &JsonValue) -> Option<&str> {
+    let content = &ev["content"];
+    match content["displayname"].as_str() {
+        Some(name) if !name.is_empty() && content["membership"] == "join" => Some(name),
+        _ => None,
+    }
+}
+
+impl StoredRoom {
+    pub fn set_state(&mut self, ev: &JsonValue) {
+        let (evtype, key) = match (ev["type"].as_str(), ev["state_key"].as_str()) {
+            (Some(t), Some(k)) => (t, k),
+            _ => return,
+        };
+
+        let old = self.state
+            .entry(String::from(evtype))
+            .or_insert_with(HashMap::new)
+            .insert(String::from(key), ev.clone());
+
+        if evtype == "m.room.member" {
+            if let Some(name) = old.as_ref().and_then(joined_name) {
+                if let Some(n) = self.names.get_mut(name) {
+                    *n -= 1;
+                }
+            }
+            if let Some(name) = joined_name(ev) {
+                *self.names.entry(String::from(name)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Adds the state event @ev if there's nothing stored for its type and
+    /// state key, for old events that can be outdated
+    pub fn add_missing_state(&mut self, ev: &JsonValue) {
+        let stored = self.state_event(ev["type"].as_str().unwrap_or(""),
+                                      ev["state_key"].as_str().unwrap_or(""));
+        if stored.is_none() {
+            self.set_state(ev);
+        }
+    }
+
+    fn state_event(&self, evtype: &str, key: &str) -> Option<&JsonValue> {
+        self.state.get(evtype).and_then(|s| s.get(key))
+    }
+
+    /// Content of the state event of type @evtype with an empty state key
+    pub fn state_content(&self, evtype: &str) -> Option<&JsonValue> {
+        self.state_event(evtype, "").map(|x| &x["content"])
+    }
+
+    /// Builds the display name counts from the state
+    fn index_names(&mut self) {
+        let mut names = HashMap::new();
+        for ev in self.state.get("m.room.member").iter().flat_map(|m| m.values()) {
+            if let Some(name) = joined_name(ev) {
+                *names.entry(String::from(name)).or_insert(0) += 1;
+            }
+        }
+        self.names = names;
+    }
+
+    /// Display name and avatar url of the member @uid from its room member
+    /// event, None if it isn't in the stored state.
+    ///
+    /// The user id is added to the name if other member has the same one,
+    /// so they can be told apart.
+    pub fn member_info(&self, uid: &str) -> Option<(String, String)> {
+        let ev = self.state_event("m.room.member", uid)?;
+
+        let content = &ev["content"];
+        let avatar = String::from(content["avatar_url"].as_str().unwrap_or(""));
+        let name = content["displayname"].as_str().unwrap_or("");
+        if name.is_empty() {
+            return Some((String::from(uid), avatar));
+        }
+
+        // the member itself is counted if it's joined
+        let own = match joined_name(ev) {
+            Some(_) => 1,
+            None => 0,
+        };
+        let dup = self.names.get(name).map_or(false, |n| *n > own);
+
+        match dup {
+            true => Some((format!("{} ({})", name, uid), avatar)),
+            false => Some((String::from(name), avatar)),
+        }
+    }
+
+    /// Joined members from the m.room.member state, None if the state
+    /// has no members
+    pub fn members(&self) -> Option<Vec<Member>> {
+        let evs = match self.state.get("m.room.member") {
+            Some(evs) if !evs.is_empty() => evs,
+            _ => return None,
+        };
+
+        let ms = evs.values()
+            .filter(|x| x["content"]["membership"] == "join")
+            .map(|x| parse_member(x))
+            .collect();
+
+        Some(ms)
+    }
+
+    /// All the state events
+    pub fn state_events(&self) -> Vec<&JsonValue> {
+        self.state.values().flat_map(|s| s.values()).collect()
+    }
+}
+
+fn store_path(userid: &str) -> Result<PathBuf, Error> {
+    let fname: String = userid.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+    Ok(xdg_dirs.place_data_file(format!("store/{}.json", fname))?)
+}
+
+impl Store {
+    pub fn new(userid: &str) -> Store {
+        Store {
+            user_id: String::from(userid),
+            next_batch: String::new(),
+            rooms: HashMap::new(),
+            filter_id: String::new(),
+            filter_json: String::new(),
+        }
+    }
+
+    /// Returns the stored data of @userid, None if there's nothing stored
+    /// or the file can't be read
+    pub fn load(userid: &str) -> Option<Store> {
+        let path = store_path(userid).ok()?;
+        let file = File::open(path).ok()?;
+        let mut store: Store = serde_json::from_reader(file).ok()?;
+        if store.user_id != userid {
+            return None;
+        }
+
+        for r in store.rooms.values_mut() {
+            r.index_names();
+        }
+        Some(store)
+    }
+
+    /// Writes the store to a temporary file that replaces the old one, so
+    /// a crash while saving doesn't leave a broken store
+    pub fn save(&self) -> Result<(), Error> {
+        let path = store_path(&self.user_id)?;
+        let tmp = path.with_extension("json.tmp");
+
+        serde_json::to_writer(File::create(&tmp)?, self)?;
+        fs::rename(tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Merges the @sync response in the stored rooms, returns true if
+    /// anything changed
+    pub fn update(&mut self, sync: &JsonValue) -> bool {
+        let rooms = &sync["rooms"];
+        let mut changed = false;
+
+        if let Some(join) = rooms["join"].as_object() {
+            for (k, room) in join {
+                if !self.rooms.contains_key(k) {
+                    changed = true;
+                }
+                let r = self.rooms.entry(k.clone()).or_insert_with(StoredRoom::default);
+
+                for ev in room["state"]["events"].as_array().unwrap_or(&vec![]) {
+                    r.set_state(ev);
+                    changed = true;
+                }
+
+                // the sync only has the summary fields that changed
+                if let Some(summary) = room["summary"].as_object() {
+                    if !r.summary.is_object() {
+                        r.summary = json!({});
+                    }
+                    for (k, v) in summary {
+                        r.summary[k] = v.clone();
+                        changed = true;
+                    }
+                }
+
+                // there's a gap between the stored events and the new
+                // ones, so the stored ones are useless
+                let timeline = &room["timeline"];
+                let limited = timeline["limited"].as_bool().unwrap_or(false);
+                if limited || (r.timeline.is_empty() && r.prev_batch.is_empty()) {
+                    r.timeline.clear();
+                    r.prev_batch = String::from(timeline["prev_batch"].as_str().unwrap_or(""));
+                    changed = true;
+                }
+
+                for ev in timeline["events"].as_array().unwrap_or(&vec![]) {
+                    if ev["state_key"].is_string() {
+                        r.set_state(ev);
+                    }
+                    r.timeline.push(ev.clone());
+                    changed = true;
+                }
+
+                if r.timeline.len() > MAX_TIMELINE_EVENTS {
+                    let n = r.timeline.len() - MAX_TIMELINE_EVENTS;
+                    r.timeline.drain(..n);
+                    r.prev_batch = String::new();
+                }
+
+                if let Some(n) = room["unread_notifications"]["notification_count"].as_i64() {
+                    changed |= r.notifications != n;
+                    r.notifications = n;
+                }
+            }
+        }
+
+        if let Some(leave) = rooms["leave"].as_object() {
+            for k in leave.keys() {
+                changed |= self.rooms.remove(k).is_some();
+            }
+        }
+
+        changed
+    }
+
+    /// Stored rooms in the sync response format, so they can be parsed
+    /// like an initial sync
+    pub fn as_sync(&self) -> JsonValue {
+        let mut join = serde_json::Map::new();
+        for (k, r) in self.rooms.iter() {
+            join.insert(k.clone(), json!({
+                "state": { "events": r.state_events() },
+                "timeline": { "events": r.timeline, "prev_batch": r.prev_batch },
+                "unread_notifications": { "notification_count": r.notifications },
+                "summary": r.summary,
+            }));
+        }
+
+        json!({
+            "next_batch": self.next_batch,
+            "rooms": { "join": join },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(uid: &str, name: &str, membership: &str) -> JsonValue {
+        json!({
+            "type": "m.room.member",
+            "state_key": uid,
+            "sender": uid,
+            "content": {"membership": membership, "displayname": name},
+        })
+    }
+
+    #[test]
+    fn set_state_replaces() {
+        let mut r = StoredRoom::default();
+        r.set_state(&json!({"type": "m.room.topic", "state_key": "", "content": {"topic": "a"}}));
+        r.set_state(&json!({"type": "m.room.topic", "state_key": "", "content": {"topic": "b"}}));
+        r.add_missing_state(&json!({"type": "m.room.topic", "state_key": "", "content": {"topic": "c"}}));
+
+        assert_eq!(r.state_events().len(), 1);
+        assert_eq!(r.state_content("m.room.topic").unwrap()["topic"], "b");
+    }
+
+    #[test]
+    fn member_info_duplicated_names() {
+        let mut r = StoredRoom::default();
+        r.set_state(&member("@bob:a.org", "Bob", "join"));
+        assert_eq!(r.member_info("@bob:a.org").unwrap().0, "Bob");
+
+        r.set_state(&member("@bob:b.org", "Bob", "join"));
+        assert_eq!(r.member_info("@bob:a.org").unwrap().0, "Bob (@bob:a.org)");
+        assert_eq!(r.member_info("@bob:b.org").unwrap().0, "Bob (@bob:b.org)");
+
+        // the members that left don't count
+        r.set_state(&member("@bob:b.org", "Bob", "leave"));
+        assert_eq!(r.member_info("@bob:a.org").unwrap().0, "Bob");
+        assert_eq!(r.member_info("@bob:b.org").unwrap().0, "Bob (@bob:b.org)");
+
+        r.set_state(&member("@bob:b.org", "Robert", "join"));
+        assert_eq!(r.member_info("@bob:a.org").unwrap().0, "Bob");
+        assert_eq!(r.member_info("@bob:b.org").unwrap().0, "Robert");
+        assert_eq!(r.members().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn names_are_indexed_on_load() {
+        let mut st = Store::new("@me:a.org");
+        let mut r = StoredRoom::default();
+        r.set_state(&member("@bob:a.org", "Bob", "join"));
+        r.set_state(&member("@bob:b.org", "Bob", "join"));
+        st.rooms.insert(String::from("!room:a.org"), r);
+
+        let js = serde_json::to_string(&st).unwrap();
+        let mut st: Store = serde_json::from_str(&js).unwrap();
+        for r in st.rooms.values_mut() {
+            r.index_names();
+        }
+
+        let r = &st.rooms["!room:a.org"];
+        assert_eq!(r.member_info("@bob:a.org").unwrap().0, "Bob (@bob:a.org)");
+        assert_eq!(st.as_sync()["rooms"]["join"]["!room:a.org"]["state"]["events"].as_array().unwrap().len(), 2);
+    }
+}
@@ -1,6 +1,8 @@
 extern crate chrono;
+extern crate serde_json;
 
 use self::chrono::prelude::*;
+use self::serde_json::Value as JsonValue;
 
 #[derive(Debug)]
 pub struct Message {
@@ -11,7 +13,14 @@ pub struct Message {
     pub room: String,
     pub thumb: String,
     pub url: String,
-    pub id: String,
+    /// Media info of the attachments: mimetype, size, w, h...
+    pub info: JsonValue,
+    /// Server id of the event, empty for local echoes not sent yet
+    pub event_id: String,
+    /// Transaction id of the messages sent by this client
+    pub txn_id: String,
+    /// Event data added by the server, not signed by the sender
+    pub unsigned: JsonValue,
 }
 
 impl Clone for Message {
@@ -24,11 +33,26 @@ impl Clone for Message {
             room: self.room.clone(),
             thumb: self.thumb.clone(),
             url: self.url.clone(),
-            id: self.id.clone(),
+            info: self.info.clone(),
+            event_id: self.event_id.clone(),
+            txn_id: self.txn_id.clone(),
+            unsigned: self.unsigned.clone(),
         }
     }
 }
 
+/// Delivery state of the local echo of a message sent by this client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MsgStatus {
+    /// waiting in the outbox for the connection
+    Queued,
+    Sending,
+    Sent,
+    Failed,
+    /// the user removed the failed message
+    Canceled,
+}
+
 #[derive(Debug)]
 pub struct Member {
     pub alias: String,
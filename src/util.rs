@@ -6,27 +6,57 @@ extern crate serde_json;
 extern crate chrono;
 extern crate time;
 extern crate cairo;
+extern crate gdk_pixbuf;
+extern crate gio;
+extern crate unicode_segmentation;
 
 use self::regex::Regex;
 
 use self::serde_json::Value as JsonValue;
 
 use self::url::Url;
-use std::io::Read;
+use self::reqwest::header::{Authorization, Bearer, ContentLength, ContentType, Range, ByteRangeSpec};
+use self::reqwest::header::{ContentRange, ContentRangeSpec};
+use self::gdk_pixbuf::Pixbuf;
+use self::gio::NetworkMonitorExt;
+use self::unicode_segmentation::UnicodeSegmentation;
+use std::cmp;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
 use std::path::Path;
+use std::thread;
+use std::time::Duration as StdDuration;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use std::fs::File;
-use std::io::prelude::*;
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
 use self::chrono::prelude::*;
-use self::time::Duration;
 
 use error::Error;
+use error::MatrixError;
 use types::Message;
+use types::Member;
 use types::Room;
+use filter;
+use mediacache;
+use pool;
+use pool::Priority;
+use settings::Autoload;
+
+
+lazy_static! {
+    /// Client shared by all the requests, so the connections are reused
+    static ref HTTP_CLIENT: reqwest::Client = {
+        reqwest::Client::new().expect("Can't create the http client")
+    };
+
+    /// When the avatar thumbnails are downloaded, until the settings are read
+    static ref AVATAR_AUTOLOAD: Mutex<Autoload> = Mutex::new(Autoload::Always);
+}
 
 
 // from https://stackoverflow.com/a/43992218/1592377
@@ -70,30 +100,31 @@ macro_rules! bkerror {
 
 #[macro_export]
 macro_rules! get {
-    ($url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
-        query!("get", $url, $attrs, $okcb, $errcb)
+    ($url: expr, $tk: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        query!("get", $url, $tk, $attrs, $okcb, $errcb)
     };
-    ($url: expr, $okcb: expr, $errcb: expr) => {
-        query!("get", $url, $okcb, $errcb)
+    ($url: expr, $tk: expr, $okcb: expr, $errcb: expr) => {
+        query!("get", $url, $tk, $okcb, $errcb)
     };
 }
 
 #[macro_export]
 macro_rules! post {
-    ($url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
-        query!("post", $url, $attrs, $okcb, $errcb)
+    ($url: expr, $tk: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        query!("post", $url, $tk, $attrs, $okcb, $errcb)
     };
-    ($url: expr, $okcb: expr, $errcb: expr) => {
-        query!("post", $url, $okcb, $errcb)
+    ($url: expr, $tk: expr, $okcb: expr, $errcb: expr) => {
+        query!("post", $url, $tk, $okcb, $errcb)
     };
 }
 
+/// Runs the request in the backend worker pool, the priority can be given
+/// before the method: `query!(Priority::High => "put", ...)`
 #[macro_export]
 macro_rules! query {
-    ($method: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
-        thread::spawn(move || {
-            let js = json_q($method, $url, $attrs);
-
+    ($prio: expr => $method: expr, $url: expr, $tk: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        json_q_pool($prio, $method, ($url).clone(), $tk.to_string(), ($attrs).clone(), MAX_RETRIES,
+                    move |js| {
             match js {
                 Ok(r) => {
                     $okcb(r)
@@ -104,9 +135,15 @@ macro_rules! query {
             }
         });
     };
-    ($method: expr, $url: expr, $okcb: expr, $errcb: expr) => {
+    ($prio: expr => $method: expr, $url: expr, $tk: expr, $okcb: expr, $errcb: expr) => {
         let attrs = json!(null);
-        query!($method, $url, &attrs, $okcb, $errcb)
+        query!($prio => $method, $url, $tk, &attrs, $okcb, $errcb)
+    };
+    ($method: expr, $url: expr, $tk: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        query!(Priority::Normal => $method, $url, $tk, $attrs, $okcb, $errcb)
+    };
+    ($method: expr, $url: expr, $tk: expr, $okcb: expr, $errcb: expr) => {
+        query!(Priority::Normal => $method, $url, $tk, $okcb, $errcb)
     };
 }
 
@@ -124,16 +161,58 @@ macro_rules! media {
 #[macro_export]
 macro_rules! thumb {
     ($base: expr, $url: expr) => {
-        dw_media($base, $url, true, None, 64, 64)
+        dw_thumb($base, $url, 64, 64)
     };
     ($base: expr, $url: expr, $size: expr) => {
-        dw_media($base, $url, true, None, $size, $size)
+        dw_thumb($base, $url, $size, $size)
     };
     ($base: expr, $url: expr, $w: expr, $h: expr) => {
-        dw_media($base, $url, true, None, $w, $h)
+        dw_thumb($base, $url, $w, $h)
     };
 }
 
+/// Builds the url of a homeserver endpoint.
+///
+/// The path of the @base url is kept, so homeservers under a path prefix
+/// work, and each @prefix and @path element is percent-encoded as a single
+/// path segment, so room, event and user ids can be used as they are.
+/// The @params are encoded as the url query.
+pub fn build_url(base: &Url,
+                 prefix: &[&str],
+                 path: &[&str],
+                 params: &[(&str, &str)])
+                 -> Result<Url, Error> {
+    let mut url = base.clone();
+
+    {
+        let mut segments = url.path_segments_mut().map_err(|_| Error::BackendError)?;
+        segments.pop_if_empty();
+        segments.extend(prefix);
+        segments.extend(path);
+    }
+
+    url.set_query(None);
+    url.set_fragment(None);
+    if !params.is_empty() {
+        let mut query = url.query_pairs_mut();
+        for &(k, v) in params {
+            query.append_pair(k, v);
+        }
+    }
+
+    Ok(url)
+}
+
+/// Url of a client-server API endpoint, `/_matrix/client/r0/<path>`
+pub fn client_url(base: &Url, path: &[&str], params: &[(&str, &str)]) -> Result<Url, Error> {
+    build_url(base, &["_matrix", "client", "r0"], path, params)
+}
+
+/// Url of a media repository endpoint, `/_matrix/media/r0/<path>`
+pub fn media_url(base: &Url, path: &[&str], params: &[(&str, &str)]) -> Result<Url, Error> {
+    build_url(base, &["_matrix", "media", "r0"], path, params)
+}
+
 pub fn evc(events: &JsonValue, t: &str, field: &str) -> String {
     if let Some(arr) = events.as_array() {
         return match arr.iter().find(|x| x["type"] == t) {
@@ -157,7 +236,7 @@ pub fn get_rooms_from_json(r: JsonValue, userid: &str) -> Result<Vec<Room>, Erro
     for k in join.keys() {
         let room = join.get(k).ok_or(Error::BackendError)?;
         let stevents = &room["state"]["events"];
-        let name = calculate_room_name(stevents, userid)?;
+        let name = calculate_room_name(stevents, &room["summary"], userid)?;
         let mut r = Room::new(k.clone(), name);
 
         r.avatar = evc(stevents, "m.room.avatar", "url");
@@ -172,7 +251,7 @@ pub fn get_rooms_from_json(r: JsonValue, userid: &str) -> Result<Vec<Room>, Erro
     Ok(rooms)
 }
 
-pub fn get_rooms_timeline_from_json(baseu: &Url, r: JsonValue) -> Result<Vec<Message>, Error> {
+pub fn get_rooms_timeline_from_json(r: JsonValue) -> Result<Vec<Message>, Error> {
     let rooms = &r["rooms"];
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
 
@@ -189,7 +268,7 @@ pub fn get_rooms_timeline_from_json(baseu: &Url, r: JsonValue) -> Result<Vec<Mes
             .filter(|x| x["type"] == "m.room.message");
 
         for ev in events {
-            let msg = parse_room_message(baseu, k.clone(), ev);
+            let msg = parse_room_message(k.clone(), ev);
             msgs.push(msg);
         }
     }
@@ -198,8 +277,7 @@ pub fn get_rooms_timeline_from_json(baseu: &Url, r: JsonValue) -> Result<Vec<Mes
 }
 
 pub fn get_media(url: &str) -> Result<Vec<u8>, Error> {
-    let client = reqwest::Client::new()?;
-    let mut conn = client.get(url)?;
+    let mut conn = HTTP_CLIENT.get(url)?;
     let mut res = conn.send()?;
 
     let mut buffer = Vec::new();
@@ -208,63 +286,425 @@ pub fn get_media(url: &str) -> Result<Vec<u8>, Error> {
     Ok(buffer)
 }
 
-pub fn dw_media(base: &Url,
-                url: &str,
-                thumb: bool,
-                dest: Option<&str>,
-                w: i32,
-                h: i32)
-                -> Result<String, Error> {
-    // TODO, don't download if exists
+/// Max width and height of the compressed images
+const MAX_IMAGE_SIZE: i32 = 2048;
+/// JPEG quality of the compressed images
+const IMAGE_QUALITY: &'static str = "85";
+
+/// File ready to upload, see read_attachment
+pub struct Attachment {
+    pub data: Vec<u8>,
+    pub name: String,
+    pub mimetype: String,
+    /// info of the message content
+    pub info: JsonValue,
+}
+
+/// Name of the file @path, without the directory
+pub fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .map(String::from)
+        .unwrap_or(String::from("file"))
+}
+
+/// Mimetype of the file @path from its extension
+pub fn guess_mimetype(path: &str) -> String {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    let mime = match &ext[..] {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "ogg" | "oga" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    };
+
+    String::from(mime)
+}
+
+/// Message type for an attachment of @mimetype
+pub fn msgtype_for(mimetype: &str) -> &'static str {
+    match mimetype.split('/').next().unwrap_or("") {
+        "image" => "m.image",
+        "video" => "m.video",
+        "audio" => "m.audio",
+        _ => "m.file",
+    }
+}
 
+/// true if the image @mimetype can be compressed without losing
+/// anything but quality, animations and vector images can't
+pub fn can_compress(mimetype: &str) -> bool {
+    match mimetype {
+        "image/png" | "image/jpeg" | "image/bmp" | "image/webp" => true,
+        _ => false,
+    }
+}
+
+/// true for the image formats that can be animated
+pub fn can_animate(mimetype: &str) -> bool {
+    match mimetype {
+        "image/gif" | "image/webp" => true,
+        _ => false,
+    }
+}
+
+/// True if the network connection is metered, as told by the
+/// GNetworkMonitor, the media isn't autoloaded on these if the user
+/// asked so, see Settings::can_autoload
+pub fn network_metered() -> bool {
+    gio::NetworkMonitor::get_default()
+        .map(|m| m.get_network_metered())
+        .unwrap_or(false)
+}
+
+/// Reads the file @path to upload it. If @compress is true the image
+/// is scaled down to MAX_IMAGE_SIZE and stored as JPEG.
+pub fn read_attachment(path: &str, compress: bool) -> Result<Attachment, Error> {
+    let mut name = file_name(path);
+    let mut mimetype = guess_mimetype(path);
+
+    let mut info = json!({ "mimetype": mimetype });
+
+    let data = match mimetype.starts_with("image/") {
+        true => {
+            let mut pixbuf = Pixbuf::new_from_file(path)?;
+            let compress = compress && can_compress(&mimetype);
+
+            if compress {
+                let (w, h) = (pixbuf.get_width(), pixbuf.get_height());
+                let scale = (MAX_IMAGE_SIZE as f64 / cmp::max(w, h) as f64).min(1.);
+                if scale < 1. {
+                    let sw = cmp::max(1, (w as f64 * scale) as i32);
+                    let sh = cmp::max(1, (h as f64 * scale) as i32);
+                    pixbuf = pixbuf.scale_simple(sw, sh, gdk_pixbuf::InterpType::Bilinear)
+                        .ok_or(Error::BackendError)?;
+                }
+            }
+
+            info["w"] = json!(pixbuf.get_width());
+            info["h"] = json!(pixbuf.get_height());
+
+            match compress {
+                true => {
+                    let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+                    let tmp = xdg_dirs.place_cache_file(format!("uploads/{}.jpg", new_txn_id()))?;
+                    let tmp = String::from(tmp.to_str().ok_or(Error::BackendError)?);
+
+                    pixbuf.savev(&tmp, "jpeg", &[("quality", IMAGE_QUALITY)])?;
+                    let mut data = vec![];
+                    File::open(&tmp)?.read_to_end(&mut data)?;
+                    let _ = fs::remove_file(&tmp);
+
+                    mimetype = String::from("image/jpeg");
+                    info["mimetype"] = json!(mimetype);
+                    let stem = Path::new(&name)
+                        .file_stem()
+                        .and_then(|f| f.to_str())
+                        .map(String::from)
+                        .unwrap_or(String::from("image"));
+                    name = format!("{}.jpg", stem);
+                    data
+                }
+                false => {
+                    let mut data = vec![];
+                    File::open(path)?.read_to_end(&mut data)?;
+                    data
+                }
+            }
+        }
+        false => {
+            let mut data = vec![];
+            File::open(path)?.read_to_end(&mut data)?;
+            data
+        }
+    };
+
+    info["size"] = json!(data.len());
+
+    Ok(Attachment {
+        data: data,
+        name: name,
+        mimetype: mimetype,
+        info: info,
+    })
+}
+
+/// Removes @path if it's an image pasted in the msg_entry, the other
+/// attachments are files of the user and they're kept.
+///
+/// Returns true if @path was a pasted image.
+pub fn remove_pasted_image(path: &str) -> bool {
     let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+    let uploads = xdg_dirs.get_cache_home().join("uploads");
+    if !Path::new(path).starts_with(&uploads) {
+        return false;
+    }
+
+    let _ = fs::remove_file(path);
+    true
+}
+
+/// Uploads the @data of the file @fname to the media repository, and
+/// returns its mxc url
+pub fn upload_media(base: &Url,
+                    tk: &str,
+                    data: Vec<u8>,
+                    mimetype: &str,
+                    fname: &str)
+                    -> Result<String, Error> {
+    let url = media_url(base, &["upload"], &[("filename", fname)])?;
+
+    let mut conn = HTTP_CLIENT.post(url.as_str())?;
+    conn.header(Authorization(Bearer { token: String::from(tk) }));
+    conn.header(ContentType(mimetype.parse().unwrap_or(ContentType::octet_stream().0)));
+    conn.body(data);
+
+    let mut res = conn.send()?;
+    let status = res.status();
+    let js: JsonValue = res.json().unwrap_or(json!(null));
+
+    if !status.is_success() {
+        return Err(Error::MatrixError(MatrixError::new(status.as_u16(), &js)));
+    }
 
+    match js["content_uri"].as_str() {
+        Some(uri) => Ok(String::from(uri)),
+        None => Err(Error::BackendError),
+    }
+}
+
+/// Mxc url of the image shown for an m.image message with the @url and
+/// @info, its thumbnail or the image itself if there's no thumbnail or
+/// it's animated, because the thumbnails are static
+pub fn image_source(url: &str, info: &JsonValue) -> String {
+    let thumb = info["thumbnail_url"].as_str().unwrap_or("");
+    let mimetype = info["mimetype"].as_str().unwrap_or("");
+
+    match thumb.is_empty() || can_animate(mimetype) {
+        true => String::from(url),
+        false => String::from(thumb),
+    }
+}
+
+/// Server name and media id of the mxc @url
+fn mxc_parts(url: &str) -> Result<(String, String), Error> {
     let re = Regex::new(r"mxc://(?P<server>[^/]+)/(?P<media>.+)")?;
     let caps = re.captures(url).ok_or(Error::BackendError)?;
-    let server = String::from(&caps["server"]);
-    let media = String::from(&caps["media"]);
+    Ok((String::from(&caps["server"]), String::from(&caps["media"])))
+}
+
+/// Download url of the media of the mxc @url
+pub fn mxc_download_url(base: &Url, url: &str) -> Result<Url, Error> {
+    let (server, media) = mxc_parts(url)?;
+    media_url(base, &["download", &server, &media], &[])
+}
+
+/// Streams the file at @url to @dest, without loading it in memory.
+///
+/// The data is written to "@dest.part", that's renamed when the download
+/// is complete. If there's a part file of an interrupted download, the
+/// download continues from its end if the server supports range requests.
+///
+/// @progress is called with the downloaded bytes and the total size, if
+/// the server sends it. The download stops if @cancel is set, removing
+/// the part file, and false is returned.
+pub fn download_file<F>(url: &str,
+                        dest: &Path,
+                        cancel: &AtomicBool,
+                        mut progress: F)
+                        -> Result<bool, Error>
+    where F: FnMut(u64, Option<u64>)
+{
+    let fname = dest.file_name().and_then(|f| f.to_str()).unwrap_or("download");
+    let part = dest.with_file_name(format!("{}.part", fname));
+    let offset = fs::metadata(&part).map(|md| md.len()).unwrap_or(0);
+
+    let mut conn = HTTP_CLIENT.get(url)?;
+    if offset > 0 {
+        conn.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+    }
+
+    let mut res = conn.send()?;
+    let status = res.status();
+
+    // the range starts at the end of the file, the previous try was
+    // interrupted after writing the last chunk
+    if status.as_u16() == 416 && offset > 0 {
+        let length = match res.headers().get::<ContentRange>() {
+            Some(&ContentRange(ContentRangeSpec::Bytes { instance_length, .. })) => instance_length,
+            _ => None,
+        };
+        if length == Some(offset) {
+            progress(offset, length);
+            fs::rename(&part, dest)?;
+            return Ok(true);
+        }
+
+        // the part file doesn't match the remote file, it's downloaded
+        // again the next time
+        let _ = fs::remove_file(&part);
+    }
+
+    if !status.is_success() {
+        return Err(Error::MatrixError(MatrixError::new(status.as_u16(), &json!(null))));
+    }
+
+    // without a partial content response the server ignored the range,
+    // so the download starts again
+    let (mut file, mut done) = match status.as_u16() {
+        206 => (OpenOptions::new().append(true).open(&part)?, offset),
+        _ => (File::create(&part)?, 0),
+    };
+    let total = res.headers().get::<ContentLength>().map(|l| l.0 + done);
+    progress(done, total);
+
+    let mut buffer = [0; 16384];
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            drop(file);
+            let _ = fs::remove_file(&part);
+            return Ok(false);
+        }
 
-    let mut url: Url;
+        let n = res.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buffer[..n])?;
+        done += n as u64;
+        progress(done, total);
+    }
 
-    if thumb {
-        url = base.join("/_matrix/media/r0/thumbnail/")?;
-        url = url.join(&(server + "/"))?;
-        let f = format!("?width={}&height={}&method=scale", w, h);
-        url = url.join(&(media.clone() + &f))?;
-    } else {
-        url = base.join("/_matrix/media/r0/download/")?;
-        url = url.join(&(server + "/"))?;
-        url = url.join(&(media))?;
+    // the connection was closed before the end, the part file is kept
+    // to resume the download
+    if total.map(|t| done < t).unwrap_or(false) {
+        return Err(Error::BackendError);
     }
 
-    let fname = match dest {
-        None => {
-            String::from(xdg_dirs.place_cache_file(&media)?.to_str().ok_or(Error::BackendError)?)
+    fs::rename(&part, dest)?;
+    Ok(true)
+}
+
+/// Downloads the media of the mxc @url, or its thumbnail of @w x @h if
+/// @thumb is true, and returns the path of the file.
+///
+/// If @dest is None the file is stored in the media cache, so it's only
+/// downloaded once, otherwise it's written in the @dest directory.
+pub fn dw_media(base: &Url,
+                url: &str,
+                thumb: bool,
+                dest: Option<&str>,
+                w: i32,
+                h: i32)
+                -> Result<String, Error> {
+    let (server, media) = mxc_parts(url)?;
+
+    let (url, cached) = match thumb {
+        true => {
+            let width = w.to_string();
+            let height = h.to_string();
+            let params = [("width", &width[..]), ("height", &height[..]), ("method", "scale")];
+            (media_url(base, &["thumbnail", &server, &media], &params)?,
+             mediacache::media_path(&server, &media, Some((w, h, "scale")))?)
         }
-        Some(d) => String::from(d) + &media,
+        false => (media_url(base, &["download", &server, &media], &[])?,
+                  mediacache::media_path(&server, &media, None)?),
     };
 
-    let pathname = fname.clone();
-    let p = Path::new(&pathname);
-    if p.is_file() {
+    if let Some(d) = dest {
+        let fname = String::from(d) + &media;
+        if Path::new(&fname).is_file() {
+            return Ok(fname);
+        }
+
+        let buffer = get_media(url.as_str())?;
+        mediacache::write_atomic(Path::new(&fname), &buffer)?;
+        return Ok(fname);
+    }
+
+    let fname = String::from(cached.to_str().ok_or(Error::BackendError)?);
+    if mediacache::hit(&cached) {
         return Ok(fname);
     }
 
-    let mut file = File::create(&fname)?;
     let buffer = get_media(url.as_str())?;
-    file.write_all(&buffer)?;
+    mediacache::store(&cached, &buffer)?;
 
     Ok(fname)
 }
 
-pub fn age_to_datetime(age: i64) -> DateTime<Local> {
-    let now = Local::now();
-    let diff = Duration::seconds(age / 1000);
-    now - diff
+/// Sets when the avatar thumbnails are downloaded, they follow the
+/// autoload policy of the message images
+pub fn set_avatar_autoload(autoload: Autoload) {
+    *AVATAR_AUTOLOAD.lock().unwrap() = autoload;
 }
 
-pub fn json_q(method: &str, url: &Url, attrs: &JsonValue) -> Result<JsonValue, Error> {
-    let client = reqwest::Client::new()?;
+/// Returns the path of the @w x @h thumbnail of the mxc @url, it's only
+/// downloaded if it isn't cached and the autoload policy allows it.
+pub fn dw_thumb(base: &Url, url: &str, w: i32, h: i32) -> Result<String, Error> {
+    let (server, media) = mxc_parts(url)?;
+    let cached = mediacache::media_path(&server, &media, Some((w, h, "scale")))?;
+
+    let allowed = AVATAR_AUTOLOAD.lock().unwrap().allows(network_metered());
+    if !allowed && !mediacache::hit(&cached) {
+        return Err(Error::BackendError);
+    }
+
+    dw_media(base, url, true, None, w, h)
+}
+
+static TXN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a new transaction id to send events, it's unique across
+/// restarts because it starts with the current time in milliseconds.
+pub fn new_txn_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(StdDuration::from_secs(0));
+    let ms = now.as_secs() * 1000 + (now.subsec_nanos() / 1000000) as u64;
+    let n = TXN_COUNTER.fetch_add(1, Ordering::SeqCst);
+    format!("m{}.{}", ms, n)
+}
+
+/// Converts the event origin_server_ts, milliseconds since the epoch, to
+/// a local date. It comes from other servers, so the values out of range
+/// are shown as the epoch instead of failing.
+pub fn ts_to_datetime(ts: i64) -> DateTime<Local> {
+    // the milliseconds are always positive, -1 is 999 ms after -1 s
+    let mut secs = ts / 1000;
+    let mut ms = ts % 1000;
+    if ms < 0 {
+        secs -= 1;
+        ms += 1000;
+    }
+
+    Local.timestamp_opt(secs, ms as u32 * 1000000)
+        .single()
+        .unwrap_or_else(|| Local.timestamp(0, 0))
+}
+
+/// Makes a json request to the @url.
+///
+/// The @tk access token is sent in the Authorization header, an empty
+/// token makes an unauthenticated request.
+pub fn json_q(method: &str, url: &Url, tk: &str, attrs: &JsonValue) -> Result<JsonValue, Error> {
+    let client = &*HTTP_CLIENT;
 
     let mut conn = match method {
         "post" => client.post(url.as_str())?,
@@ -273,45 +713,304 @@ pub fn json_q(method: &str, url: &Url, attrs: &JsonValue) -> Result<JsonValue, E
         _ => client.get(url.as_str())?,
     };
 
+    if !tk.is_empty() {
+        conn.header(Authorization(Bearer { token: String::from(tk) }));
+    }
+
     let conn2 = conn.json(attrs)?;
     let mut res = conn2.send()?;
+    let status = res.status();
+
+    let js: JsonValue = match res.json() {
+        Ok(js) => js,
+        Err(_) if status.is_success() => return Err(Error::BackendError),
+        Err(_) => json!(null),
+    };
+
+    if !status.is_success() || !js["errcode"].is_null() {
+        return Err(Error::MatrixError(MatrixError::new(status.as_u16(), &js)));
+    }
+
+    Ok(js)
+}
+
+/// Number of times a request is retried before giving up
+pub const MAX_RETRIES: u32 = 3;
+/// Initial backoff delay for transient errors, doubled on each attempt
+const RETRY_BASE_MS: u64 = 500;
+/// Upper limit for the backoff delay
+const RETRY_MAX_MS: u64 = 60000;
+
+/// Returns the milliseconds to wait before retrying a request that failed
+/// with @err, or None if the request shouldn't be retried.
+///
+/// Rate limited requests wait the `retry_after_ms` given by the server,
+/// network errors and server errors use exponential backoff with jitter.
+/// A success response that isn't json, like the page of a captive portal
+/// or a proxy, is a transient error too.
+pub fn retry_delay(err: &Error, attempt: u32) -> Option<u64> {
+    let backoff = || {
+        let exp = RETRY_BASE_MS.saturating_mul(1 << attempt.min(16));
+        let delay = exp.min(RETRY_MAX_MS);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        // random jitter of up to a half of the delay
+        delay / 2 + nanos % (delay / 2 + 1)
+    };
+
+    match *err {
+        Error::MatrixError(ref e) if is_rate_limited(e) => {
+            Some(e.retry_after_ms.unwrap_or_else(backoff))
+        }
+        Error::MatrixError(ref e) if e.status >= 500 => Some(backoff()),
+        Error::ReqwestError(_) => Some(backoff()),
+        Error::BackendError => Some(backoff()),
+        _ => None,
+    }
+}
+
+/// True if the server rejected the request because of the rate limit
+fn is_rate_limited(err: &MatrixError) -> bool {
+    err.errcode == "M_LIMIT_EXCEEDED" || err.status == 429
+}
+
+/// True if a request with @method can be sent again after the transient
+/// error @err. A rate limited request wasn't done, so any method is sent
+/// again. For the other errors the POSTs aren't idempotent, a login,
+/// register or message that timed out after the server got it would be
+/// done twice. The PUTs are only used with a transaction id, so the
+/// server ignores the repeated ones.
+pub fn can_retry(method: &str, err: &Error) -> bool {
+    match *err {
+        Error::MatrixError(ref e) if is_rate_limited(e) => true,
+        _ => method == "get" || method == "put",
+    }
+}
+
+/// Makes a json request like `json_q` retrying it while it fails with
+/// a transient error, up to @retries times or forever if None. The POSTs
+/// are only retried if they're rate limited, see `can_retry`.
+///
+/// It sleeps between the retries, so it's for the threads that only
+/// wait for this request, like the sync. The pool jobs use `json_q_pool`.
+///
+/// The @onretry callback is called with the error and the delay before
+/// each retry.
+pub fn json_q_retry<F>(method: &str,
+                       url: &Url,
+                       tk: &str,
+                       attrs: &JsonValue,
+                       retries: Option<u32>,
+                       onretry: F)
+                       -> Result<JsonValue, Error>
+    where F: Fn(&Error, u64)
+{
+    let mut attempt = 0;
+
+    loop {
+        let err = match json_q(method, url, tk, attrs) {
+            Ok(js) => return Ok(js),
+            Err(err) => err,
+        };
 
-    //let mut content = String::new();
-    //res.read_to_string(&mut content);
-    //cb(content);
+        if !can_retry(method, &err) || retries.map_or(false, |r| attempt >= r) {
+            return Err(err);
+        }
+
+        match retry_delay(&err, attempt) {
+            Some(delay) => {
+                onretry(&err, delay);
+                thread::sleep(StdDuration::from_millis(delay));
+            }
+            None => return Err(err),
+        };
 
-    match res.json() {
-        Ok(js) => Ok(js),
-        Err(_) => Err(Error::BackendError),
+        attempt += 1;
     }
 }
 
-pub fn get_user_avatar(baseu: &Url, userid: &str) -> Result<(String, String), Error> {
-    let url = baseu.join("/_matrix/client/r0/profile/")?.join(userid)?;
+/// Makes a json request in the worker pool with priority @prio and
+/// calls @cb with the result. Transient errors are retried like in
+/// `json_q_retry`, up to @retries times, but the retries are queued in
+/// the pool after the backoff delay so no worker sleeps waiting.
+pub fn json_q_pool<F>(prio: Priority,
+                      method: &'static str,
+                      url: Url,
+                      tk: String,
+                      attrs: JsonValue,
+                      retries: u32,
+                      cb: F)
+    where F: FnOnce(Result<JsonValue, Error>) + Send + 'static
+{
+    let q = PoolQuery {
+        prio: prio,
+        method: method,
+        url: url,
+        tk: tk,
+        attrs: attrs,
+        retries: retries,
+    };
+    pool::spawn(prio, move || json_q_attempt(q, 0, cb));
+}
+
+/// Request made by `json_q_pool`, kept between the retries
+struct PoolQuery {
+    prio: Priority,
+    method: &'static str,
+    url: Url,
+    tk: String,
+    attrs: JsonValue,
+    retries: u32,
+}
+
+fn json_q_attempt<F>(q: PoolQuery, attempt: u32, cb: F)
+    where F: FnOnce(Result<JsonValue, Error>) + Send + 'static
+{
+    let err = match json_q(q.method, &q.url, &q.tk, &q.attrs) {
+        Ok(js) => return cb(Ok(js)),
+        Err(err) => err,
+    };
+
+    let delay = match can_retry(q.method, &err) && attempt < q.retries {
+        true => retry_delay(&err, attempt),
+        false => None,
+    };
+
+    match delay {
+        Some(delay) => {
+            let prio = q.prio;
+            pool::spawn_after(prio, StdDuration::from_millis(delay), move || {
+                json_q_attempt(q, attempt + 1, cb)
+            });
+        }
+        None => cb(Err(err)),
+    };
+}
+
+/// Size of the avatars and identicons in logical pixels, the biggest
+/// avatar shown. The files are multiplied by the scale factor.
+pub const AVATAR_SIZE: i32 = 40;
+
+pub fn get_user_avatar(baseu: &Url, userid: &str, size: i32) -> Result<(String, String), Error> {
+    let url = client_url(baseu, &["profile", userid], &[])?;
     let attrs = json!(null);
 
-    match json_q("get", &url, &attrs) {
+    match json_q("get", &url, "", &attrs) {
         Ok(js) => {
             let name = String::from(js["displayname"].as_str().unwrap_or("@"));
-            match js["avatar_url"].as_str() {
-                Some(url) => Ok((name.clone(), thumb!(baseu, &url)?)),
-                None => Ok((name.clone(), draw_identicon(userid, name)?)),
-            }
+            let avatar = js["avatar_url"].as_str().unwrap_or("");
+            get_member_avatar(baseu, userid, name, avatar, size)
+        }
+        Err(_) => {
+            let fname = draw_identicon(userid, String::from(userid), size)?;
+            Ok((String::from(userid), fname))
+        }
+    }
+}
+
+/// Returns the @name and the avatar file of a member, the @avatar mxc
+/// thumbnail or an identicon if it's empty, of @size x @size pixels
+pub fn get_member_avatar(baseu: &Url,
+                         userid: &str,
+                         name: String,
+                         avatar: &str,
+                         size: i32)
+                         -> Result<(String, String), Error> {
+    match avatar {
+        "" => {
+            let fname = draw_identicon(userid, name.clone(), size)?;
+            Ok((name, fname))
+        }
+        url => {
+            // the thumbnail isn't downloaded if the autoload policy
+            // doesn't allow it
+            let fname = match thumb!(baseu, url, size) {
+                Ok(f) => f,
+                Err(_) => draw_identicon(userid, name.clone(), size)?,
+            };
+            Ok((name, fname))
         }
-        Err(_) => Ok((String::from(userid), draw_identicon(userid, String::from(&userid[1..2]))?)),
     }
 }
 
+/// Returns the server name of a matrix user id, `@user:example.org` gives
+/// `example.org`. Returns None if the string isn't a full user id.
+pub fn get_server_name(userid: &str) -> Option<String> {
+    if !userid.starts_with('@') {
+        return None;
+    }
+
+    match userid.splitn(2, ':').nth(1) {
+        Some(s) if !s.is_empty() => Some(String::from(s)),
+        _ => None,
+    }
+}
+
+/// Looks for the homeserver of the server name using the
+/// `/.well-known/matrix/client` file and validates it asking for the
+/// supported client-server spec versions.
+///
+/// If there's no well-known file the server name is used as homeserver,
+/// any other error, like an invalid file, is returned so the user writes
+/// the server url. Returns the homeserver url and the list of supported
+/// versions.
+pub fn discover_homeserver(server_name: &str) -> Result<(String, Vec<String>), Error> {
+    let base = format!("https://{}", server_name);
+    let url = Url::parse(&base)?.join("/.well-known/matrix/client")?;
+
+    let homeserver = match json_q("get", &url, "", &json!(null)) {
+        Ok(js) => {
+            let u = js["m.homeserver"]["base_url"].as_str().ok_or(Error::BackendError)?;
+            String::from(u.trim_right_matches('/'))
+        }
+        Err(Error::MatrixError(ref err)) if err.status == 404 => base,
+        Err(err) => return Err(err),
+    };
+
+    let url = build_url(&Url::parse(&homeserver)?, &["_matrix", "client"], &["versions"], &[])?;
+    let js = json_q("get", &url, "", &json!(null))?;
+    let versions = js["versions"].as_array().ok_or(Error::BackendError)?
+        .iter()
+        .filter_map(|v| v.as_str())
+        .map(String::from)
+        .collect();
+
+    Ok((homeserver, versions))
+}
+
 pub fn get_room_st(base: &Url, tk: &str, roomid: &str) -> Result<JsonValue, Error> {
-    let mut url = base.join("/_matrix/client/r0/rooms/")?
-        .join(&(format!("{}/state", roomid)))?;
-    url = url.join(&format!("?access_token={}", tk))?;
+    let url = client_url(base, &["rooms", roomid, "state"], &[])?;
     let attrs = json!(null);
-    let st = json_q("get", &url, &attrs)?;
+    let st = json_q("get", &url, tk, &attrs)?;
     Ok(st)
 }
 
-pub fn get_room_avatar(base: &Url, tk: &str, userid: &str, roomid: &str) -> Result<String, Error> {
+/// Uploads the @filter json and returns its id, to use it in the sync
+pub fn upload_filter(base: &Url, tk: &str, userid: &str, filter: &str) -> Result<String, Error> {
+    let url = client_url(base, &["user", userid, "filter"], &[])?;
+    let attrs: JsonValue = serde_json::from_str(filter)?;
+    let r = json_q("post", &url, tk, &attrs)?;
+    let id = r["filter_id"].as_str().ok_or(Error::BackendError)?;
+    Ok(String::from(id))
+}
+
+/// Returns the pagination token to get the events before @evid
+pub fn get_event_token(base: &Url, tk: &str, roomid: &str, evid: &str) -> Result<String, Error> {
+    let url = client_url(base, &["rooms", roomid, "context", evid], &[("limit", "0")])?;
+    let attrs = json!(null);
+    let r = json_q("get", &url, tk, &attrs)?;
+    let token = r["start"].as_str().ok_or(Error::BackendError)?;
+    Ok(String::from(token))
+}
+
+pub fn get_room_avatar(base: &Url,
+                       tk: &str,
+                       userid: &str,
+                       roomid: &str,
+                       size: i32)
+                       -> Result<String, Error> {
     let st = get_room_st(base, tk, roomid)?;
     let events = st.as_array().ok_or(Error::BackendError)?;
 
@@ -329,13 +1028,13 @@ pub fn get_room_avatar(base: &Url, tk: &str, userid: &str, roomid: &str) -> Resu
     };
 
     let mut fname = match members.count() {
-        1 => thumb!(&base, m1).unwrap_or(String::new()),
+        1 => thumb!(&base, m1, size).unwrap_or(String::new()),
         _ => String::new(),
     };
 
     if fname.is_empty() {
-        let roomname = calculate_room_name(&st, userid)?;
-        fname = draw_identicon(roomid, roomname)?;
+        let roomname = calculate_room_name(&st, &JsonValue::Null, userid)?;
+        fname = draw_identicon(roomid, roomname, size)?;
     }
 
     Ok(fname)
@@ -347,13 +1046,28 @@ struct Color {
     b: i32,
 }
 
-pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+/// FNV-1a hash of @s, unlike the std hasher it's the same on every run
+/// and rust version, so the identicon colors don't change
+pub fn stable_hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
 }
 
-pub fn draw_identicon(fname: &str, name: String) -> Result<String, Error> {
+/// First grapheme of @name in upper case, skipping the sigils of the
+/// user ids and room aliases, "X" if there's nothing left
+pub fn identicon_letter(name: &str) -> String {
+    let name = name.trim_left_matches(|c: char| c == '@' || c == '#' || c == '!' || c == '+' ||
+                                                c.is_whitespace());
+
+    match name.graphemes(true).next() {
+        Some(g) => g.to_uppercase(),
+        None => String::from("X"),
+    }
+}
+
+/// Draws an identicon of @size x @size pixels with the first letter of
+/// @name, and returns the png file. The color depends on the user or
+/// room @id, that's the file name too.
+pub fn draw_identicon(id: &str, name: String, size: i32) -> Result<String, Error> {
     let colors = vec![
         Color { r: 69,  g: 189, b: 243, },
         Color { r: 224, g: 143, b: 112, },
@@ -367,37 +1081,47 @@ pub fn draw_identicon(fname: &str, name: String) -> Result<String, Error> {
     ];
 
     let xdg_dirs = xdg::BaseDirectories::with_prefix("guillotine").unwrap();
+    let c = &colors[(stable_hash(id) % colors.len() as u64) as usize];
+
+    let fname = format!("{}-{}", id, size);
     let fname =
         String::from(xdg_dirs.place_cache_file(fname)?.to_str().ok_or(Error::BackendError)?);
 
-    let image = cairo::ImageSurface::create(cairo::Format::ARgb32, 40, 40)?;
+    let image = cairo::ImageSurface::create(cairo::Format::ARgb32, size, size)?;
     let g = cairo::Context::new(&image);
+    let s = size as f64;
 
-    let c = &colors[calculate_hash(&fname) as usize % colors.len() as usize];
     g.set_source_rgba(c.r as f64 / 256., c.g as f64 / 256., c.b as f64 / 256., 1.);
-    g.rectangle(0., 0., 40., 40.);
+    g.rectangle(0., 0., s, s);
     g.fill();
 
-    g.set_font_size(24.);
+    g.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+    g.set_font_size(s * 0.6);
     g.set_source_rgb(1.0, 1.0, 1.0);
 
-    let first = match &name.chars().nth(0) {
-        &Some(f) if f == '#' => String::from(&name.to_uppercase()[1..2]),
-        &Some(_) => String::from(&name.to_uppercase()[0..1]),
-        &None => String::from("X"),
-    };
+    let first = identicon_letter(&name);
 
+    // centered by its ink rectangle
     let te = g.text_extents(&first);
-    g.move_to(20. - te.width / 2., 20. + te.height / 2.);
+    g.move_to(s / 2. - te.width / 2. - te.x_bearing, s / 2. - te.height / 2. - te.y_bearing);
     g.show_text(&first);
 
-    let mut buffer = File::create(&fname)?;
+    // several threads can draw the same identicon
+    let mut buffer = vec![];
     image.write_to_png(&mut buffer)?;
+    mediacache::write_atomic(Path::new(&fname), &buffer)?;
 
     Ok(fname)
 }
 
-pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<String, Error> {
+/// Name of the room with the state events @roomst. The rooms without a
+/// name or alias are named after the members, the @summary heroes if
+/// the sync has it, because the members are lazy loaded and the state
+/// doesn't have all of them.
+pub fn calculate_room_name(roomst: &JsonValue,
+                           summary: &JsonValue,
+                           userid: &str)
+                           -> Result<String, Error> {
 
     // looking for "m.room.name" event
     let events = roomst.as_array().ok_or(Error::BackendError)?;
@@ -409,6 +1133,33 @@ pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<String, E
         return Ok(String::from(name["content"]["alias"].as_str().unwrap_or("WRONG ALIAS")));
     }
 
+    if let Some(heroes) = summary["m.heroes"].as_array() {
+        let names: Vec<String> = heroes.iter()
+            .filter_map(|h| h.as_str())
+            .filter(|h| *h != userid)
+            .map(|h| {
+                let name = events.iter()
+                    .find(|x| x["type"] == "m.room.member" && x["state_key"] == h)
+                    .and_then(|x| x["content"]["displayname"].as_str())
+                    .unwrap_or("");
+                String::from(if name.is_empty() { h } else { name })
+            })
+            .collect();
+
+        let count = summary["m.joined_member_count"].as_u64().unwrap_or(0) +
+                    summary["m.invited_member_count"].as_u64().unwrap_or(0);
+        let others = count.saturating_sub(1) as usize;
+
+        let name = match names.len() {
+            0 => String::from("EMPTY ROOM"),
+            n if others > n => format!("{} and Others", names[0]),
+            1 => names[0].clone(),
+            2 => format!("{} and {}", names[0], names[1]),
+            _ => format!("{} and Others", names[0]),
+        };
+        return Ok(name);
+    }
+
     // we look for members that aren't me
     let filter = |x: &&JsonValue| {
         (x["type"] == "m.room.member" && x["content"]["membership"] == "join" &&
@@ -436,39 +1187,52 @@ pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<String, E
     Ok(name)
 }
 
-pub fn parse_room_message(baseu: &Url, roomid: String, msg: &JsonValue) -> Message {
+/// Message of the m.room.message event @msg. The media isn't downloaded,
+/// the widgets ask for it when it's shown.
+pub fn parse_room_message(roomid: String, msg: &JsonValue) -> Message {
     let sender = msg["sender"].as_str().unwrap_or("");
-    let age = msg["age"].as_i64().unwrap_or(0);
-    let id = msg["id"].as_str().unwrap_or("");
+    let ts = msg["origin_server_ts"].as_i64().unwrap_or(0);
+    let id = msg["event_id"].as_str().unwrap_or("");
+    let unsigned = &msg["unsigned"];
+    let txn_id = unsigned["transaction_id"].as_str().unwrap_or("");
 
     let c = &msg["content"];
     let mtype = c["msgtype"].as_str().unwrap_or("");
     let body = c["body"].as_str().unwrap_or("");
 
-    let mut url = String::new();
-    let mut thumb = String::new();
-
-    match mtype {
-        "m.image" => {
-            url = String::from(c["url"].as_str().unwrap_or(""));
-            let mut t = String::from(c["info"]["thumbnail_url"].as_str().unwrap_or(""));
-            if t.is_empty() && !url.is_empty() {
-                t = url.clone();
-            }
-            thumb = media!(baseu, &t).unwrap_or(String::from(""));
-        }
-        _ => {}
+    let url = match mtype {
+        "m.image" | "m.file" | "m.video" | "m.audio" => String::from(c["url"].as_str().unwrap_or("")),
+        _ => String::new(),
     };
 
     Message {
         sender: String::from(sender),
         mtype: String::from(mtype),
         body: String::from(body),
-        date: age_to_datetime(age),
+        date: ts_to_datetime(ts),
         room: roomid.clone(),
         url: url,
-        thumb: thumb,
-        id: String::from(id),
+        thumb: String::new(),
+        info: c["info"].clone(),
+        event_id: String::from(id),
+        txn_id: String::from(txn_id),
+        unsigned: unsigned.clone(),
+    }
+}
+
+/// Readable size of @bytes, like "1.5 MB"
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < units.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, units[unit]),
     }
 }
 
@@ -486,61 +1250,391 @@ pub fn markup(s: &str) -> String {
     out
 }
 
-/// Recursive function that tries to get at least @get Messages for the room.
+/// Room messages returned by get_initial_room_messages
+#[derive(Debug)]
+pub struct MessagesPage {
+    pub msgs: Vec<Message>,
+    /// m.room.member state events of the senders
+    pub members: Vec<JsonValue>,
+    /// token to get older messages
+    pub prev_batch: String,
+    /// true if there's nothing older
+    pub reached_start: bool,
+}
+
+/// Max number of requests to fill a page of room messages
+const MAX_BACKFILL_PAGES: usize = 5;
+/// Max number of events requested to the server in one request
+const MAX_PAGE_LIMIT: i32 = 100;
+
+/// Gets at least @get Messages for the room paginating backwards, or less
+/// if the start of the room is reached.
 ///
-/// The @limit is the first "limit" param in the GET request.
+/// The @limit is the first "limit" param in the GET request, it's doubled
+/// for each new page up to MAX_PAGE_LIMIT and there are at most
+/// MAX_BACKFILL_PAGES requests.
 /// The @end param is used as "from" param in the GET request, so we'll get
-/// messages before that.
+/// messages before that. With None we get the latest messages.
+///
+/// The members are lazy loaded, so the page comes with the member events
+/// of the senders.
 pub fn get_initial_room_messages(baseu: &Url,
                                  tk: String,
                                  roomid: String,
                                  get: usize,
                                  limit: i32,
                                  end: Option<String>)
-                                 -> Result<(Vec<Message>, String, String), Error> {
-    let mut url =
-        baseu.join("/_matrix/client/r0/rooms/")?.join(&(roomid.clone() + "/"))?.join("messages")?;
-    let mut params = format!("?access_token={}&dir=b&limit={}", tk, limit);
+                                 -> Result<MessagesPage, Error> {
+    let filter = filter::messages_filter().to_json();
     let mut ms: Vec<Message> = vec![];
-    let mut nstart;
-    let mut nend;
+    let mut members: Vec<JsonValue> = vec![];
+    let mut from = end;
+    let mut limit = limit;
+    let mut prev_batch = from.clone().unwrap_or(String::from(""));
+    let mut reached_start = false;
+
+    for _ in 0..MAX_BACKFILL_PAGES {
+        let url = {
+            let l = limit.to_string();
+            let mut params = vec![("dir", "b"), ("limit", &l[..]), ("filter", &filter[..])];
+            if let Some(ref f) = from {
+                params.push(("from", &f[..]));
+            }
+            client_url(baseu, &["rooms", &roomid, "messages"], &params)?
+        };
+        let r = json_q("get", &url, &tk, &json!(null))?;
+
+        let empty = vec![];
+        let chunk = r["chunk"].as_array().unwrap_or(&empty);
+        let mut page: Vec<Message> = vec![];
+        for msg in chunk.iter().rev() {
+            match msg["type"].as_str().unwrap_or("") {
+                "m.room.create" => reached_start = true,
+                "m.room.message" => {
+                    page.push(parse_room_message(roomid.clone(), msg));
+                }
+                _ => {}
+            }
+        }
+        page.extend(ms);
+        ms = page;
 
-    match end {
-        Some(ref e) => {
-            params = params + &format!("&from={}", e);
+        for ev in r["state"].as_array().unwrap_or(&empty) {
+            if ev["type"] == "m.room.member" {
+                members.push(ev.clone());
+            }
         }
-        None => {}
-    };
 
-    url = url.join(&params)?;
+        // an empty page or a token that doesn't move means that there's
+        // nothing older
+        match r["end"].as_str() {
+            Some(e) if !chunk.is_empty() && Some(e) != from.as_ref().map(|f| &f[..]) => {
+                prev_batch = String::from(e);
+            }
+            _ => reached_start = true,
+        };
+
+        if reached_start || ms.len() >= get {
+            break;
+        }
+
+        from = Some(prev_batch.clone());
+        limit = cmp::min(limit * 2, MAX_PAGE_LIMIT);
+    }
+
+    Ok(MessagesPage {
+        msgs: ms,
+        members: members,
+        prev_batch: prev_batch,
+        reached_start: reached_start,
+    })
+}
+
+/// Returns the ids of the joined rooms with m.room.member events in the
+/// sync response @r
+pub fn rooms_with_member_events(r: &JsonValue) -> Vec<String> {
+    let mut rooms = vec![];
+    if let Some(join) = r["rooms"]["join"].as_object() {
+        for (k, room) in join {
+            let empty = vec![];
+            let state = room["state"]["events"].as_array().unwrap_or(&empty);
+            let timeline = room["timeline"]["events"].as_array().unwrap_or(&empty);
+            if state.iter().chain(timeline.iter()).any(|x| x["type"] == "m.room.member") {
+                rooms.push(k.clone());
+            }
+        }
+    }
+    rooms
+}
+
+/// Member from its m.room.member state event
+pub fn parse_member(ev: &JsonValue) -> Member {
+    let content = &ev["content"];
+    Member {
+        alias: String::from(content["displayname"].as_str().unwrap_or("")),
+        uid: String::from(ev["state_key"].as_str().unwrap_or("")),
+        avatar: String::from(content["avatar_url"].as_str().unwrap_or("")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
 
-    let r = json_q("get", &url, &json!(null))?;
-    nend = String::from(r["end"].as_str().unwrap_or(""));
-    nstart = String::from(r["start"].as_str().unwrap_or(""));
+    #[test]
+    fn build_url_keeps_base_path() {
+        let url = client_url(&base("https://example.org/matrix/"), &["sync"], &[]).unwrap();
+        assert_eq!(url.as_str(), "https://example.org/matrix/_matrix/client/r0/sync");
 
-    let array = r["chunk"].as_array();
-    if array.is_none() || array.unwrap().len() == 0 {
-        return Ok((ms, nstart, nend));
+        let url = client_url(&base("https://example.org/matrix"), &["sync"], &[]).unwrap();
+        assert_eq!(url.as_str(), "https://example.org/matrix/_matrix/client/r0/sync");
+
+        let url = media_url(&base("https://example.org"), &["download", "example.org", "abc"], &[]).unwrap();
+        assert_eq!(url.as_str(), "https://example.org/_matrix/media/r0/download/example.org/abc");
     }
 
-    for msg in array.unwrap().iter().rev() {
-        if msg["type"].as_str().unwrap_or("") != "m.room.message" {
-            continue;
+    #[test]
+    fn build_url_drops_base_query() {
+        let url = client_url(&base("https://example.org/?a=b#frag"), &["sync"], &[]).unwrap();
+        assert_eq!(url.as_str(), "https://example.org/_matrix/client/r0/sync");
+    }
+
+    #[test]
+    fn build_url_encodes_ids_as_segments() {
+        let ids = ["!room/id:example.org", "#alias:example.org", "$ev/ent?:example.org", "@üser:exämple.org"];
+        for id in ids.iter() {
+            let url = client_url(&base("https://example.org/prefix"), &["rooms", id, "state"], &[]).unwrap();
+            let segments: Vec<&str> = url.path_segments().unwrap().collect();
+            assert_eq!(segments.len(), 7);
+            assert_eq!(url::percent_encoding::percent_decode(segments[5].as_bytes()).decode_utf8().unwrap(), *id);
+            assert_eq!(segments[6], "state");
+            assert_eq!(url.query(), None);
+            assert_eq!(url.fragment(), None);
         }
 
-        let m = parse_room_message(&baseu, roomid.clone(), msg);
-        ms.push(m);
+        let url = client_url(&base("https://example.org"), &["rooms", "!a/b:c", "join"], &[]).unwrap();
+        assert_eq!(url.path(), "/_matrix/client/r0/rooms/!a%2Fb:c/join");
+
+        let url = client_url(&base("https://example.org"), &["directory", "room", "#a:b"], &[]).unwrap();
+        assert_eq!(url.path(), "/_matrix/client/r0/directory/room/%23a:b");
+
+        let url = client_url(&base("https://example.org"), &["profile", "@ü:b"], &[]).unwrap();
+        assert_eq!(url.path(), "/_matrix/client/r0/profile/@%C3%BC:b");
+    }
+
+    #[test]
+    fn build_url_query() {
+        let params = [("from", "t1 2&3"), ("dir", "b"), ("filter", "{\"a\":1}")];
+        let url = client_url(&base("https://example.org"), &["rooms", "!a:b", "messages"], &params).unwrap();
+
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(pairs,
+                   vec![(String::from("from"), String::from("t1 2&3")),
+                        (String::from("dir"), String::from("b")),
+                        (String::from("filter"), String::from("{\"a\":1}"))]);
+        assert_eq!(url.query(), Some("from=t1+2%263&dir=b&filter=%7B%22a%22%3A1%7D"));
+    }
+
+    fn sync_fixture() -> JsonValue {
+        serde_json::from_str(include_str!("../tests/fixtures/sync.json")).unwrap()
+    }
+
+    fn fixture_messages() -> Vec<Message> {
+        get_rooms_timeline_from_json(sync_fixture()).unwrap()
+    }
+
+    #[test]
+    fn ts_to_datetime_values() {
+        let d = ts_to_datetime(1432735824653).with_timezone(&Utc);
+        assert_eq!(d.to_rfc3339(), "2015-05-27T14:10:24.653+00:00");
+
+        let d = ts_to_datetime(-1).with_timezone(&Utc);
+        assert_eq!((d.timestamp(), d.timestamp_subsec_millis()), (-1, 999));
+
+        let d = ts_to_datetime(-1500).with_timezone(&Utc);
+        assert_eq!((d.timestamp(), d.timestamp_subsec_millis()), (-2, 500));
     }
 
-    if ms.len() < get {
-        let (more, s, e) =
-            get_initial_room_messages(baseu, tk, roomid, get, limit * 2, Some(nend))?;
-        nstart = s;
-        nend = e;
-        for m in more.iter().rev() {
-            ms.insert(0, m.clone());
+    #[test]
+    fn ts_to_datetime_out_of_range() {
+        for ts in [i64::max_value(), i64::min_value()].iter() {
+            assert_eq!(ts_to_datetime(*ts).with_timezone(&Utc).timestamp(), 0);
         }
     }
 
-    Ok((ms, nstart, nend))
+    #[test]
+    fn parse_room_message_fixture() {
+        let msgs = fixture_messages();
+        // the member event isn't a message
+        assert_eq!(msgs.len(), 4);
+
+        let text = &msgs[0];
+        assert_eq!(text.room, "!room:example.org");
+        assert_eq!(text.sender, "@alice:example.org");
+        assert_eq!(text.mtype, "m.text");
+        assert_eq!(text.body, "Hello ✨");
+        assert_eq!(text.event_id, "$text:example.org");
+        assert_eq!(text.txn_id, "m1432735824653.0");
+        assert_eq!(text.unsigned["age"], 1234);
+        assert_eq!(text.date.with_timezone(&Utc).timestamp_millis(), 1432735824653);
+        assert_eq!(text.url, "");
+
+        let image = &msgs[1];
+        assert_eq!(image.event_id, "$image:example.org");
+        assert_eq!(image.txn_id, "");
+        assert_eq!(image.unsigned["age"], 800);
+        assert_eq!(image.url, "mxc://example.org/cat");
+        assert_eq!(image.thumb, "");
+        assert_eq!(image.info["mimetype"], "image/png");
+        assert_eq!(image.date.with_timezone(&Utc).timestamp_millis(), 1432735825000);
+    }
+
+    #[test]
+    fn parse_room_message_bad_timestamps() {
+        let msgs = fixture_messages();
+
+        let negative = &msgs[2];
+        assert_eq!(negative.event_id, "$negative:example.org");
+        assert!(negative.unsigned.is_null());
+        assert_eq!(negative.date.with_timezone(&Utc).timestamp_millis(), -1);
+
+        let huge = &msgs[3];
+        assert_eq!(huge.event_id, "$huge:example.org");
+        assert_eq!(huge.date.with_timezone(&Utc).timestamp(), 0);
+    }
+
+    #[test]
+    fn parse_room_message_missing_fields() {
+        let msg = parse_room_message(String::from("!room:example.org"), &json!({}));
+        assert_eq!(msg.event_id, "");
+        assert_eq!(msg.txn_id, "");
+        assert!(msg.unsigned.is_null());
+        assert_eq!(msg.date.with_timezone(&Utc).timestamp(), 0);
+    }
+
+    #[test]
+    fn identicon_letter_graphemes() {
+        assert_eq!(identicon_letter("alice"), "A");
+        assert_eq!(identicon_letter("😀 smile"), "😀");
+        assert_eq!(identicon_letter("👩\u{200d}👩\u{200d}👧 family"), "👩\u{200d}👩\u{200d}👧");
+        assert_eq!(identicon_letter("e\u{301}mile"), "E\u{301}");
+        assert_eq!(identicon_letter("東京"), "東");
+        assert_eq!(identicon_letter("ßig"), "SS");
+    }
+
+    #[test]
+    fn identicon_letter_sigils() {
+        assert_eq!(identicon_letter("@alice:example.org"), "A");
+        assert_eq!(identicon_letter("#room:example.org"), "R");
+        assert_eq!(identicon_letter("!abc:example.org"), "A");
+        assert_eq!(identicon_letter("+group:example.org"), "G");
+        assert_eq!(identicon_letter("  @#bob"), "B");
+    }
+
+    #[test]
+    fn identicon_letter_empty() {
+        assert_eq!(identicon_letter(""), "X");
+        assert_eq!(identicon_letter("   "), "X");
+        assert_eq!(identicon_letter("@"), "X");
+        assert_eq!(identicon_letter("@#!+"), "X");
+    }
+
+    #[test]
+    fn stable_hash_values() {
+        // the colors of the identicons depend on these values, they must
+        // not change between runs or versions
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash("@alice:example.org"), 0x0862de498e9b8025);
+        assert_eq!(stable_hash("!room:example.org"), 0xd713515065cf1b5f);
+        assert_eq!(stable_hash("@alice:example.org"), stable_hash("@alice:example.org"));
+    }
+
+    fn matrix_error(status: u16, js: JsonValue) -> Error {
+        Error::MatrixError(MatrixError::new(status, &js))
+    }
+
+    #[test]
+    fn can_retry_rate_limited() {
+        let limited = matrix_error(429, json!({"errcode": "M_LIMIT_EXCEEDED", "retry_after_ms": 2000}));
+        assert!(can_retry("post", &limited));
+        assert!(can_retry("get", &limited));
+        assert_eq!(retry_delay(&limited, 0), Some(2000));
+
+        let limited = matrix_error(429, json!(null));
+        assert!(can_retry("post", &limited));
+        assert!(retry_delay(&limited, 0).is_some());
+    }
+
+    #[test]
+    fn can_retry_by_method() {
+        let err = matrix_error(502, json!(null));
+        assert!(can_retry("get", &err));
+        assert!(can_retry("put", &err));
+        assert!(!can_retry("post", &err));
+        assert!(!can_retry("delete", &err));
+        assert!(retry_delay(&err, 0).is_some());
+
+        let forbidden = matrix_error(403, json!({"errcode": "M_FORBIDDEN"}));
+        assert_eq!(retry_delay(&forbidden, 0), None);
+    }
+
+    #[test]
+    fn retry_delay_bad_response() {
+        // the sync keeps trying behind a captive portal
+        assert!(retry_delay(&Error::BackendError, 0).is_some());
+        assert!(retry_delay(&Error::BackendError, 20).unwrap() <= RETRY_MAX_MS);
+    }
+
+    fn member(uid: &str, name: &str) -> JsonValue {
+        json!({
+            "type": "m.room.member",
+            "state_key": uid,
+            "sender": uid,
+            "content": {"membership": "join", "displayname": name},
+        })
+    }
+
+    #[test]
+    fn calculate_room_name_heroes() {
+        // lazy loaded members, only the heroes are in the state
+        let st = json!([member("@bob:example.org", "Bob")]);
+        let summary = json!({
+            "m.heroes": ["@bob:example.org"],
+            "m.joined_member_count": 2,
+            "m.invited_member_count": 0,
+        });
+        let name = calculate_room_name(&st, &summary, "@me:example.org").unwrap();
+        assert_eq!(name, "Bob");
+
+        let summary = json!({
+            "m.heroes": ["@bob:example.org", "@carol:example.org"],
+            "m.joined_member_count": 3,
+        });
+        let name = calculate_room_name(&st, &summary, "@me:example.org").unwrap();
+        assert_eq!(name, "Bob and @carol:example.org");
+
+        let summary = json!({
+            "m.heroes": ["@bob:example.org", "@carol:example.org"],
+            "m.joined_member_count": 10,
+        });
+        let name = calculate_room_name(&st, &summary, "@me:example.org").unwrap();
+        assert_eq!(name, "Bob and Others");
+    }
+
+    #[test]
+    fn calculate_room_name_without_summary() {
+        let st = json!([member("@me:example.org", "Me"), member("@bob:example.org", "Bob")]);
+        let name = calculate_room_name(&st, &JsonValue::Null, "@me:example.org").unwrap();
+        assert_eq!(name, "Bob");
+
+        let st = json!([{"type": "m.room.name", "state_key": "", "content": {"name": "Room"}}]);
+        let summary = json!({"m.heroes": ["@bob:example.org"]});
+        let name = calculate_room_name(&st, &summary, "@me:example.org").unwrap();
+        assert_eq!(name, "Room");
+    }
 }
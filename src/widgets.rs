@@ -1,23 +1,39 @@
 extern crate gtk;
+extern crate gdk;
 extern crate gdk_pixbuf;
+extern crate glib;
 extern crate chrono;
 extern crate pango;
+extern crate cairo;
 
 use self::gdk_pixbuf::Pixbuf;
+use self::gdk_pixbuf::PixbufAnimation;
+use self::gdk_pixbuf::PixbufAnimationIter;
+use self::gdk_pixbuf::PixbufLoader;
 use self::gtk::prelude::*;
 
 use types::Message;
+use types::MsgStatus;
 use types::Member;
 use types::Room;
 
 use self::chrono::prelude::*;
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+
 use backend::BKCommand;
+use backend::Download;
 
 use util;
-
-use std::sync::mpsc::channel;
-use std::sync::mpsc::{Sender, Receiver};
+use mainloop;
+use blurhash;
 
 use app::AppOp;
 
@@ -26,6 +42,7 @@ pub struct MessageBox<'a> {
     msg: &'a Message,
     op: &'a AppOp,
     username: gtk::Label,
+    status: gtk::Box,
 }
 
 // Room Search item
@@ -37,7 +54,14 @@ pub struct RoomBox<'a> {
 impl<'a> MessageBox<'a> {
     pub fn new(msg: &'a Message, op: &'a AppOp) -> MessageBox<'a> {
         let username = gtk::Label::new("");
-        MessageBox { msg: msg, op: op, username }
+        let status = gtk::Box::new(gtk::Orientation::Horizontal, 3);
+        MessageBox { msg: msg, op: op, username, status }
+    }
+
+    /// Box with the delivery state, it's empty for the remote messages,
+    /// see set_msg_status
+    pub fn status(&self) -> gtk::Box {
+        self.status.clone()
     }
 
     pub fn widget(&self) -> gtk::Box {
@@ -72,13 +96,11 @@ impl<'a> MessageBox<'a> {
 
         content.pack_start(&info, false, false, 0);
 
-        let body: gtk::Box;
-
-        if msg.mtype == "m.image" {
-            body = self.build_room_msg_image();
-        } else {
-            body = self.build_room_msg_body(&msg.body);
-        }
+        let body = match &msg.mtype[..] {
+            "m.image" => self.build_room_msg_image(),
+            "m.file" | "m.video" | "m.audio" => self.build_room_msg_file(),
+            _ => self.build_room_msg_body(&msg.body),
+        };
 
         content.pack_start(&body, true, true, 0);
 
@@ -87,23 +109,30 @@ impl<'a> MessageBox<'a> {
 
     fn build_room_msg_avatar(&self) -> gtk::Image {
         let sender = self.msg.sender.clone();
+        let room = self.msg.room.clone();
         let backend = self.op.backend.clone();
+        let gen = self.op.room_gen;
         let avatar = gtk::Image::new_from_icon_name("image-missing", 5);
-        let a = avatar.clone();
         let u = self.username.clone();
 
-        let (tx, rx): (Sender<(String, String)>, Receiver<(String, String)>) = channel();
-        backend.send(BKCommand::GetUserInfoAsync(sender, tx)).unwrap();
-        gtk::timeout_add(50, move || match rx.try_recv() {
-            Err(_) => gtk::Continue(true),
-            Ok((name, avatar)) => {
-                if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&avatar, 32, 32, false) {
-                    a.set_from_pixbuf(&pixbuf);
+        // the avatar file is downloaded for the scale factor, so it's
+        // requested again if it changes
+        let load = move |image: &gtk::Image| {
+            let a = image.clone();
+            let u = u.clone();
+            let (tx, rx) = mainloop::channel::<(String, String)>();
+            backend.send(BKCommand::GetUserInfoAsync(room.clone(), Some(gen), sender.clone(), tx)).unwrap();
+            rx.attach(move |(name, avatar)| {
+                // the lookup was dropped because the room changed
+                if name.is_empty() {
+                    return;
                 }
+                set_image_file(&a, &avatar, 32, true);
                 u.set_markup(&format!("<b>{}</b>", name));
-                gtk::Continue(false)
-            }
-        });
+            });
+        };
+        load(&avatar);
+        connect_scale_changed(&avatar, load);
         avatar.set_alignment(0.5, 0.);
 
         avatar
@@ -140,22 +169,101 @@ impl<'a> MessageBox<'a> {
         let msg = self.msg;
         let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
         let image = gtk::Image::new();
+        let viewbtn = gtk::Button::new();
 
-        if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&msg.thumb, 200, 200, true) {
-            image.set_from_pixbuf(&pixbuf);
+        let mimetype = String::from(msg.info["mimetype"].as_str().unwrap_or(""));
+        let autoplay = self.op.settings.autoplay_animations;
+        let mut overlay = None;
+        // the click that loads the image doesn't open it too
+        let loaded = Rc::new(Cell::new(false));
+
+        // the local echoes have the file on disk, the remote images are
+        // downloaded when they're scrolled into view, or when they're
+        // clicked if the autoload settings don't allow it
+        if !msg.thumb.is_empty() {
+            show_message_image(&image, &viewbtn, &msg.thumb, &mimetype, autoplay);
+            loaded.set(true);
         } else {
-            image.set_from_file(&msg.thumb);
+            let w = msg.info["w"].as_i64().unwrap_or(0) as i32;
+            let h = msg.info["h"].as_i64().unwrap_or(0) as i32;
+            let hash = msg.info["xyz.amorgan.blurhash"].as_str()
+                .or(msg.info["blurhash"].as_str())
+                .unwrap_or("");
+            set_image_placeholder(&image, w, h, hash, 200);
+
+            let source = util::image_source(&msg.url, &msg.info);
+            if !source.is_empty() {
+                let scroll: gtk::ScrolledWindow = self.op.gtk_builder
+                    .get_object("messages_scroll")
+                    .expect("Can't find messages_scroll in ui file.");
+                let size = match source == msg.url {
+                    true => msg.info["size"].as_u64(),
+                    false => msg.info["thumbnail_info"]["size"].as_u64(),
+                };
+                let size = size.unwrap_or(0);
+
+                let backend = self.op.backend.clone();
+                let gen = self.op.room_gen;
+                let btn = viewbtn.clone();
+                let ld = loaded.clone();
+                let load = move |image: &gtk::Image| {
+                    let image = image.clone();
+                    let btn = btn.clone();
+                    let mimetype = mimetype.clone();
+                    let ld = ld.clone();
+                    let (tx, rx) = mainloop::channel::<String>();
+                    backend.send(BKCommand::GetMediaAsync(source.clone(), gen, tx)).unwrap();
+                    rx.attach(move |fname| if !fname.is_empty() {
+                        image.set_size_request(-1, -1);
+                        show_message_image(&image, &btn, &fname, &mimetype, autoplay);
+                        ld.set(true);
+                    });
+                };
+
+                match self.op.settings.can_autoload(size, util::network_metered()) {
+                    true => when_visible(&image, &scroll, load),
+                    false => overlay = Some(click_to_load(&image, &viewbtn, size, load)),
+                };
+            }
         }
 
-        let viewbtn = gtk::Button::new();
         let url = msg.url.clone();
-        viewbtn.connect_clicked(move |_| {
+        viewbtn.connect_clicked(move |_| if loaded.get() {
             println!("Download and show a dialog: {}", url);
         });
 
-        viewbtn.set_image(&image);
+        match overlay {
+            Some(ref o) => viewbtn.add(o),
+            None => viewbtn.set_image(&image),
+        };
 
         bx.add(&viewbtn);
+        if !msg.url.is_empty() {
+            bx.pack_start(&MediaDownload::new(msg, self.op).widget(), false, false, 5);
+        }
+        bx
+    }
+
+    fn build_room_msg_file(&self) -> gtk::Box {
+        let msg = self.msg;
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+
+        let icon = match &msg.mtype[..] {
+            "m.video" => "video-x-generic-symbolic",
+            "m.audio" => "audio-x-generic-symbolic",
+            _ => "text-x-generic-symbolic",
+        };
+        let image = gtk::Image::new_from_icon_name(icon, 1);
+
+        let name = gtk::Label::new(msg.body.as_str());
+        name.set_ellipsize(pango::EllipsizeMode::Middle);
+        name.set_halign(gtk::Align::Start);
+
+        bx.pack_start(&image, false, false, 0);
+        bx.pack_start(&name, false, false, 0);
+        if !msg.url.is_empty() {
+            bx.pack_start(&MediaDownload::new(msg, self.op).widget(), false, false, 0);
+        }
         bx
     }
 
@@ -185,6 +293,7 @@ impl<'a> MessageBox<'a> {
 
         info.pack_start(&username, true, true, 0);
         info.pack_start(&date, false, false, 0);
+        info.pack_start(&self.status, false, false, 5);
 
         info
     }
@@ -211,24 +320,27 @@ impl<'a> RoomBox<'a> {
         };
 
         let avatar = gtk::Image::new_from_icon_name("image-missing", 5);
-        let a = avatar.clone();
         let id = r.id.clone();
         let name = mname.clone();
-        let (tx, rx): (Sender<String>, Receiver<String>) = channel();
-        self.op.backend.send(BKCommand::GetThumbAsync(r.avatar.clone(), tx)).unwrap();
-        gtk::timeout_add(50, move || match rx.try_recv() {
-            Err(_) => gtk::Continue(true),
-            Ok(fname) => {
+        let media = r.avatar.clone();
+        let backend = self.op.backend.clone();
+        let load = move |image: &gtk::Image| {
+            let a = image.clone();
+            let id = id.clone();
+            let name = name.clone();
+            let (tx, rx) = mainloop::channel::<String>();
+            backend.send(BKCommand::GetThumbAsync(media.clone(), tx)).unwrap();
+            rx.attach(move |fname| {
                 let mut f = fname.clone();
                 if f.is_empty() {
-                    f = util::draw_identicon(&id, name.clone()).unwrap();
+                    let size = util::AVATAR_SIZE * a.get_scale_factor();
+                    f = util::draw_identicon(&id, name.clone(), size).unwrap_or(f);
                 }
-                if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&f, 32, 32, false) {
-                    a.set_from_pixbuf(&pixbuf);
-                }
-                gtk::Continue(false)
-            }
-        });
+                set_image_file(&a, &f, 32, true);
+            });
+        };
+        load(&avatar);
+        connect_scale_changed(&avatar, load);
         w.pack_start(&avatar, false, false, 0);
 
         let b = gtk::Box::new(gtk::Orientation::Vertical, 0);
@@ -277,3 +389,745 @@ impl<'a> RoomBox<'a> {
         h
     }
 }
+
+/// Local echo of a message sent by this client, it's shown in the
+/// message_list until the server echoes it back
+#[derive(Clone)]
+pub struct LocalEcho {
+    pub msg: Message,
+    /// MessageBox widget and its status box
+    pub row: gtk::Box,
+    pub status_box: gtk::Box,
+    /// shared with the retry and cancel buttons
+    pub status: Rc<Cell<MsgStatus>>,
+    /// local file and compression of an attachment, until it's uploaded
+    pub attachment: Option<(String, bool)>,
+}
+
+impl LocalEcho {
+    pub fn new(msg: &Message, op: &AppOp) -> LocalEcho {
+        let mb = MessageBox::new(msg, op);
+        LocalEcho {
+            msg: msg.clone(),
+            row: mb.widget(),
+            status_box: mb.status(),
+            status: Rc::new(Cell::new(MsgStatus::Sending)),
+            attachment: None,
+        }
+    }
+
+    /// Rebuilds the widgets, the old ones are gone after a room change
+    pub fn rebuild(&mut self, op: &AppOp) {
+        let mb = MessageBox::new(&self.msg, op);
+        self.row = mb.widget();
+        self.status_box = mb.status();
+    }
+
+    /// Shows the delivery @status in the status box.
+    ///
+    /// Failed messages get a retry button, that sends the message again
+    /// with the same transaction id, and failed and queued messages get a
+    /// cancel button that removes them.
+    pub fn set_status(&self, status: MsgStatus, backend: &Sender<BKCommand>) {
+        self.status.set(status);
+
+        let status_box = &self.status_box;
+        for ch in status_box.get_children().iter() {
+            status_box.remove(ch);
+        }
+
+        match status {
+            MsgStatus::Queued => {
+                let image = gtk::Image::new_from_icon_name("network-offline-symbolic", 1);
+                image.set_tooltip_text("Waiting for the connection");
+
+                let cancel = self.cancel_button(backend);
+
+                status_box.pack_start(&image, false, false, 0);
+                status_box.pack_start(&cancel, false, false, 0);
+            }
+            MsgStatus::Sending => {
+                let spinner = gtk::Spinner::new();
+                spinner.start();
+                spinner.set_tooltip_text("Sending…");
+                status_box.pack_start(&spinner, false, false, 0);
+            }
+            MsgStatus::Sent => {
+                let image = gtk::Image::new_from_icon_name("emblem-ok-symbolic", 1);
+                image.set_tooltip_text("Sent");
+                status_box.pack_start(&image, false, false, 0);
+            }
+            MsgStatus::Failed => {
+                let label = gtk::Label::new("");
+                label.set_markup("<span color=\"red\">Not sent</span>");
+
+                let retry = gtk::Button::new_from_icon_name("view-refresh-symbolic", 1);
+                retry.set_tooltip_text("Retry");
+                retry.set_relief(gtk::ReliefStyle::None);
+
+                let echo = self.clone();
+                let b = backend.clone();
+                retry.connect_clicked(move |_| {
+                    echo.set_status(MsgStatus::Sending, &b);
+                    let cmd = match echo.attachment {
+                        Some((ref path, compress)) if echo.msg.url.is_empty() => {
+                            BKCommand::AttachFile(echo.msg.clone(), path.clone(), compress)
+                        }
+                        _ => BKCommand::SendMsg(echo.msg.clone()),
+                    };
+                    b.send(cmd).unwrap();
+                });
+
+                let cancel = self.cancel_button(backend);
+
+                status_box.pack_start(&label, false, false, 0);
+                status_box.pack_start(&retry, false, false, 0);
+                status_box.pack_start(&cancel, false, false, 0);
+            }
+            MsgStatus::Canceled => {
+                backend.send(BKCommand::CancelMsg(self.msg.txn_id.clone())).unwrap();
+                if let Some((ref path, _)) = self.attachment {
+                    util::remove_pasted_image(path);
+                }
+                if let Some(p) = self.row.get_parent() {
+                    p.destroy();
+                }
+            }
+        };
+
+        status_box.show_all();
+    }
+
+    fn cancel_button(&self, backend: &Sender<BKCommand>) -> gtk::Button {
+        let cancel = gtk::Button::new_from_icon_name("window-close-symbolic", 1);
+        cancel.set_tooltip_text("Cancel");
+        cancel.set_relief(gtk::ReliefStyle::None);
+
+        let echo = self.clone();
+        let b = backend.clone();
+        cancel.connect_clicked(move |_| echo.set_status(MsgStatus::Canceled, &b));
+
+        cancel
+    }
+}
+
+/// Shows the image file @fname in @image at @size x @size logical pixels,
+/// rendered for the scale factor of the widget so it's sharp on HiDPI
+/// screens. With @crop the image fills the square keeping its aspect
+/// ratio, for the avatars, otherwise it fits in it.
+///
+/// Returns false if the file can't be loaded.
+pub fn set_image_file(image: &gtk::Image, fname: &str, size: i32, crop: bool) -> bool {
+    let scale = image.get_scale_factor();
+    let px = size * scale;
+
+    let pixbuf = match crop {
+        true => cropped_pixbuf(fname, px),
+        false => Pixbuf::new_from_file_at_scale(fname, px, px, true).ok(),
+    };
+
+    match pixbuf.and_then(|p| gdk::cairo_surface_create_from_pixbuf(&p, scale, None)) {
+        Some(surface) => {
+            image.set_from_surface(&surface);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Shows the image file @fname of a message in @image, playing it if
+/// it's an animation of @mimetype, see AnimatedImage::connect
+fn show_message_image(image: &gtk::Image, button: &gtk::Button, fname: &str, mimetype: &str, autoplay: bool) {
+    let anim = match util::can_animate(mimetype) {
+        true => load_animation(fname, mimetype),
+        false => None,
+    };
+
+    match anim {
+        Some(anim) => {
+            let a = AnimatedImage::new(anim, image, 200);
+            a.connect(button, autoplay);
+        }
+        None => {
+            if !set_image_file(image, fname, 200, false) {
+                image.set_from_file(fname);
+            }
+            let fname = String::from(fname);
+            connect_scale_changed(image, move |i| { set_image_file(i, &fname, 200, false); });
+        }
+    };
+}
+
+/// Width of the decoded blurhash, it's scaled up to the image size
+const BLURHASH_WIDTH: i32 = 32;
+
+/// Shows a placeholder for a message image of @w x @h pixels, fitted in
+/// @size, so the timeline doesn't jump when the image is loaded. It's
+/// the blurhash @hash of the image, or an icon if there's no hash or
+/// the size is unknown.
+fn set_image_placeholder(image: &gtk::Image, w: i32, h: i32, hash: &str, size: i32) {
+    if w <= 0 || h <= 0 {
+        image.set_from_icon_name("image-x-generic", 6);
+        return;
+    }
+
+    let s = f64::min(1.0, size as f64 / cmp::max(w, h) as f64);
+    let fw = cmp::max(1, (w as f64 * s).round() as i32);
+    let fh = cmp::max(1, (h as f64 * s).round() as i32);
+    image.set_size_request(fw, fh);
+
+    let scale = image.get_scale_factor();
+    let bw = cmp::min(fw, BLURHASH_WIDTH);
+    let bh = cmp::max(1, fh * bw / fw);
+
+    let surface = blurhash::decode(hash, bw as usize, bh as usize, 1.0)
+        .map(|px| Pixbuf::new_from_vec(px, gdk_pixbuf::Colorspace::Rgb, false, 8, bw, bh, bw * 3))
+        .and_then(|p| p.scale_simple(fw * scale, fh * scale, gdk_pixbuf::InterpType::Bilinear))
+        .and_then(|p| gdk::cairo_surface_create_from_pixbuf(&p, scale, None));
+
+    match surface {
+        Some(surface) => image.set_from_surface(&surface),
+        None => image.set_from_icon_name("image-x-generic", 6),
+    };
+}
+
+/// Shows a "click to load" label over the placeholder @image, the image
+/// of @size bytes is loaded with @load the first time the @button is
+/// clicked.
+/// Returns the overlay with both, to be added to the @button.
+fn click_to_load<F: Fn(&gtk::Image) + 'static>(image: &gtk::Image,
+                                               button: &gtk::Button,
+                                               size: u64,
+                                               load: F)
+                                               -> gtk::Overlay {
+    let text = match size {
+        0 => String::from("Click to load"),
+        s => format!("Click to load ({})", util::human_size(s)),
+    };
+    let label = gtk::Label::new("");
+    label.set_markup(&format!("<span background=\"black\" bgalpha=\"60%\" foreground=\"white\"> {} </span>", text));
+    label.set_halign(gtk::Align::Center);
+    label.set_valign(gtk::Align::Center);
+
+    let overlay = gtk::Overlay::new();
+    overlay.add(image);
+    overlay.add_overlay(&label);
+
+    let requested = Cell::new(false);
+    let image = image.clone();
+    button.connect_clicked(move |_| if !requested.get() {
+        requested.set(true);
+        label.hide();
+        load(&image);
+    });
+
+    overlay
+}
+
+/// Scrolled window that contains @widget, if any
+fn scroll_of<W: IsA<gtk::Widget>>(widget: &W) -> Option<gtk::ScrolledWindow> {
+    widget.get_ancestor(gtk::ScrolledWindow::static_type())
+        .and_then(|w| w.downcast::<gtk::ScrolledWindow>().ok())
+}
+
+/// True if @image is shown in the visible area of @scroll
+fn is_visible_in(image: &gtk::Image, scroll: &gtk::ScrolledWindow) -> bool {
+    if !image.get_mapped() {
+        return false;
+    }
+
+    match image.translate_coordinates(scroll, 0, 0) {
+        Some((_, y)) => y + image.get_allocated_height() >= 0 && y <= scroll.get_allocated_height(),
+        None => false,
+    }
+}
+
+/// Calls @f once, the first time @image is scrolled into the visible
+/// area of @scroll, so the media of the messages that aren't seen isn't
+/// downloaded
+fn when_visible<F: FnOnce(&gtk::Image) + 'static>(image: &gtk::Image, scroll: &gtk::ScrolledWindow, f: F) {
+    let adj = match scroll.get_vadjustment() {
+        Some(adj) => adj,
+        None => return f(image),
+    };
+
+    let f = Rc::new(RefCell::new(Some(f)));
+    let ids = Rc::new(Cell::new((0, 0)));
+
+    let check = {
+        let image = image.clone();
+        let scroll = scroll.clone();
+        let adj = adj.clone();
+        let f = f.clone();
+        let ids = ids.clone();
+        Rc::new(move || {
+            if f.borrow().is_none() || !is_visible_in(&image, &scroll) {
+                return;
+            }
+            let (adj_id, alloc_id) = ids.get();
+            glib::signal::signal_handler_disconnect(&adj, adj_id);
+            glib::signal::signal_handler_disconnect(&image, alloc_id);
+
+            let f = f.borrow_mut().take();
+            if let Some(f) = f {
+                f(&image);
+            }
+        })
+    };
+
+    let c = check.clone();
+    let adj_id = adj.connect_value_changed(move |_| c());
+    let c = check.clone();
+    let alloc_id = image.connect_size_allocate(move |_, _| c());
+    ids.set((adj_id, alloc_id));
+
+    // the adjustment outlives the messages
+    image.connect_destroy(move |_| if f.borrow_mut().take().is_some() {
+        glib::signal::signal_handler_disconnect(&adj, adj_id);
+    });
+}
+
+/// @pixbuf scaled down to fit in @size x @size pixels
+fn fit_pixbuf(pixbuf: &Pixbuf, size: i32) -> Option<Pixbuf> {
+    let (w, h) = (pixbuf.get_width(), pixbuf.get_height());
+    if w <= size && h <= size {
+        return Some(pixbuf.clone());
+    }
+
+    let s = size as f64 / cmp::max(w, h) as f64;
+    let sw = cmp::max(1, (w as f64 * s).round() as i32);
+    let sh = cmp::max(1, (h as f64 * s).round() as i32);
+    pixbuf.scale_simple(sw, sh, gdk_pixbuf::InterpType::Bilinear)
+}
+
+/// Loads the animation of the file @fname with the loader of its
+/// @mimetype, the cached files have no extension. Returns None if it
+/// can't be loaded or it's a single frame.
+fn load_animation(fname: &str, mimetype: &str) -> Option<PixbufAnimation> {
+    let mut data = vec![];
+    File::open(fname).and_then(|mut f| f.read_to_end(&mut data)).ok()?;
+
+    let loader = PixbufLoader::new_with_mime_type(mimetype).unwrap_or(PixbufLoader::new());
+    let r = loader.loader_write(&data).and_then(|_| loader.close());
+    if r.is_err() {
+        return None;
+    }
+
+    match loader.get_animation() {
+        Some(ref anim) if anim.is_static_image() => None,
+        anim => anim,
+    }
+}
+
+/// Shortest delay between frames, in ms. Many gifs have delays of 0 or
+/// 10 ms that browsers play slower.
+const MIN_FRAME_DELAY: i32 = 20;
+
+/// Animated image of a message. The frames are scaled to fit in @size
+/// logical pixels, for the scale factor of the widget.
+#[derive(Clone)]
+struct AnimatedImage {
+    anim: PixbufAnimation,
+    image: gtk::Image,
+    size: i32,
+    playing: Rc<Cell<bool>>,
+    /// increased on each play, so a stopped loop doesn't go on if the
+    /// animation is played again before its next frame
+    gen: Rc<Cell<u64>>,
+    /// Frames of the animation already scaled, with the scale factor of
+    /// the surfaces
+    frames: Rc<RefCell<(i32, Vec<(Pixbuf, cairo::Surface)>)>>,
+}
+
+impl AnimatedImage {
+    fn new(anim: PixbufAnimation, image: &gtk::Image, size: i32) -> AnimatedImage {
+        let a = AnimatedImage {
+            anim: anim,
+            image: image.clone(),
+            size: size,
+            playing: Rc::new(Cell::new(false)),
+            gen: Rc::new(Cell::new(0)),
+            frames: Rc::new(RefCell::new((0, vec![]))),
+        };
+        a.show_static();
+        a
+    }
+
+    /// Plays the animation while the image is in the visible area of the
+    /// scrolled window if @autoplay is true, or while the pointer is over
+    /// the @button otherwise
+    fn connect(&self, button: &gtk::Button, autoplay: bool) {
+        if autoplay {
+            // the rows scrolled out of view are still mapped, so the
+            // scroll position is checked too
+            let watch = Rc::new(RefCell::new(None));
+            let a = self.clone();
+            let w = watch.clone();
+            let mapped = Rc::new(move || {
+                if w.borrow().is_none() {
+                    *w.borrow_mut() = a.watch_scroll();
+                }
+                a.play_if_visible();
+            });
+
+            let m = mapped.clone();
+            self.image.connect_map(move |_| m());
+            let a = self.clone();
+            self.image.connect_size_allocate(move |_, _| a.play_if_visible());
+
+            // the adjustment outlives the messages
+            self.image.connect_destroy(move |_| {
+                if let Some((adj, id)) = watch.borrow_mut().take() {
+                    glib::signal::signal_handler_disconnect(&adj, id);
+                }
+            });
+
+            // loaded after the image was shown
+            if self.image.get_mapped() {
+                mapped();
+            }
+        } else {
+            let a = self.clone();
+            button.connect_enter_notify_event(move |_, _| {
+                a.play();
+                Inhibit(false)
+            });
+            let a = self.clone();
+            button.connect_leave_notify_event(move |_, _| {
+                a.stop();
+                Inhibit(false)
+            });
+        }
+
+        let a = self.clone();
+        self.image.connect_unmap(move |_| a.stop());
+
+        let a = self.clone();
+        connect_scale_changed(&self.image, move |_| if !a.playing.get() {
+            a.show_static();
+        });
+    }
+
+    /// Connects to the scroll of the message list to play the animation
+    /// only while it's visible, returns the adjustment and the handler id
+    fn watch_scroll(&self) -> Option<(gtk::Adjustment, u64)> {
+        let adj = scroll_of(&self.image)?.get_vadjustment()?;
+        let a = self.clone();
+        let id = adj.connect_value_changed(move |_| a.play_if_visible());
+        Some((adj, id))
+    }
+
+    fn play_if_visible(&self) {
+        let visible = match scroll_of(&self.image) {
+            Some(scroll) => is_visible_in(&self.image, &scroll),
+            None => self.image.get_mapped(),
+        };
+
+        match visible {
+            true => self.play(),
+            false => self.stop(),
+        };
+    }
+
+    /// Shows the frame @pixbuf, each frame is scaled once and kept, the
+    /// animation loops over the same ones
+    fn show_frame(&self, pixbuf: &Pixbuf) {
+        let scale = self.image.get_scale_factor();
+        let mut frames = self.frames.borrow_mut();
+        if frames.0 != scale {
+            *frames = (scale, vec![]);
+        }
+
+        if let Some(&(_, ref surface)) = frames.1.iter().find(|f| f.0 == *pixbuf) {
+            self.image.set_from_surface(surface);
+            return;
+        }
+
+        let surface = fit_pixbuf(pixbuf, self.size * scale)
+            .and_then(|p| gdk::cairo_surface_create_from_pixbuf(&p, scale, None));
+        if let Some(surface) = surface {
+            self.image.set_from_surface(&surface);
+            frames.1.push((pixbuf.clone(), surface));
+        }
+    }
+
+    fn show_static(&self) {
+        if let Some(pixbuf) = self.anim.get_static_image() {
+            self.show_frame(&pixbuf);
+        }
+    }
+
+    fn play(&self) {
+        if self.playing.get() {
+            return;
+        }
+        self.playing.set(true);
+        self.gen.set(self.gen.get() + 1);
+
+        let iter = self.anim.get_iter(&glib::get_current_time());
+        self.next_frame(iter, self.gen.get());
+    }
+
+    fn stop(&self) {
+        self.playing.set(false);
+    }
+
+    /// Shows the current frame of @iter and waits for the next one, one
+    /// timeout per frame because each frame has its own delay
+    fn next_frame(&self, iter: PixbufAnimationIter, gen: u64) {
+        if self.gen.get() != gen {
+            return;
+        }
+        if !self.playing.get() {
+            self.show_static();
+            return;
+        }
+
+        self.show_frame(&iter.get_pixbuf());
+
+        // a negative delay means this frame is shown forever
+        let delay = iter.get_delay_time();
+        if delay < 0 {
+            return;
+        }
+
+        let a = self.clone();
+        let mut iter = Some(iter);
+        gtk::timeout_add(cmp::max(delay, MIN_FRAME_DELAY) as u32, move || {
+            if let Some(iter) = iter.take() {
+                iter.advance(&glib::get_current_time());
+                a.next_frame(iter, gen);
+            }
+            gtk::Continue(false)
+        });
+    }
+}
+
+/// Pixbuf of @fname scaled to cover @size x @size pixels and cropped to
+/// the center, so non square images aren't distorted
+fn cropped_pixbuf(fname: &str, size: i32) -> Option<Pixbuf> {
+    let pixbuf = Pixbuf::new_from_file(fname).ok()?;
+    let (w, h) = (pixbuf.get_width(), pixbuf.get_height());
+    let s = size as f64 / cmp::min(w, h) as f64;
+    let sw = cmp::max(size, (w as f64 * s).round() as i32);
+    let sh = cmp::max(size, (h as f64 * s).round() as i32);
+
+    let scaled = pixbuf.scale_simple(sw, sh, gdk_pixbuf::InterpType::Bilinear)?;
+    Some(scaled.new_subpixbuf((sw - size) / 2, (sh - size) / 2, size, size))
+}
+
+/// Calls @f when the scale factor of @image changes, to render it again
+pub fn connect_scale_changed<F: Fn(&gtk::Image) + 'static>(image: &gtk::Image, f: F) {
+    let last = Cell::new(image.get_scale_factor());
+    image.connect_property_scale_factor_notify(move |image| {
+        let scale = image.get_scale_factor();
+        if scale != last.get() {
+            last.set(scale);
+            f(image);
+        }
+    });
+}
+
+/// "Save as" button of a media message. While the file is downloading
+/// it's replaced with a progress bar and a cancel button.
+#[derive(Clone)]
+struct MediaDownload {
+    url: String,
+    name: String,
+    bx: gtk::Box,
+    window: gtk::Window,
+    backend: Sender<BKCommand>,
+}
+
+impl MediaDownload {
+    fn new(msg: &Message, op: &AppOp) -> MediaDownload {
+        let window: gtk::Window = op.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+
+        MediaDownload {
+            url: msg.url.clone(),
+            name: msg.body.clone(),
+            bx: gtk::Box::new(gtk::Orientation::Horizontal, 3),
+            window: window,
+            backend: op.backend.clone(),
+        }
+    }
+
+    fn widget(&self) -> gtk::Box {
+        self.show_save_button();
+        self.bx.clone()
+    }
+
+    fn clear(&self) {
+        for ch in self.bx.get_children().iter() {
+            self.bx.remove(ch);
+        }
+    }
+
+    fn show_save_button(&self) {
+        self.clear();
+
+        let save = gtk::Button::new_from_icon_name("document-save-symbolic", 1);
+        save.set_tooltip_text("Save as…");
+        save.set_relief(gtk::ReliefStyle::None);
+        save.set_valign(gtk::Align::Center);
+
+        let d = self.clone();
+        save.connect_clicked(move |_| d.save_as());
+
+        self.bx.pack_start(&save, false, false, 0);
+        self.bx.show_all();
+    }
+
+    /// Shows the save button with the @markup status, and a retry button
+    /// if @dest is given, it resumes the download
+    fn show_result(&self, markup: &str, dest: Option<String>) {
+        self.show_save_button();
+
+        let label = gtk::Label::new("");
+        label.set_markup(markup);
+        self.bx.pack_start(&label, false, false, 0);
+
+        if let Some(dest) = dest {
+            let retry = gtk::Button::new_from_icon_name("view-refresh-symbolic", 1);
+            retry.set_tooltip_text("Resume");
+            retry.set_relief(gtk::ReliefStyle::None);
+
+            let d = self.clone();
+            retry.connect_clicked(move |_| d.download(dest.clone()));
+            self.bx.pack_start(&retry, false, false, 0);
+        }
+
+        self.bx.show_all();
+    }
+
+    fn save_as(&self) {
+        let dialog = gtk::FileChooserDialog::new(Some("Save as"),
+                                                 Some(&self.window),
+                                                 gtk::FileChooserAction::Save);
+        dialog.add_button("_Cancel", gtk::ResponseType::Cancel.into());
+        dialog.add_button("_Save", gtk::ResponseType::Accept.into());
+        dialog.set_do_overwrite_confirmation(true);
+        dialog.set_current_name(&self.name);
+
+        let dest = match dialog.run() == gtk::ResponseType::Accept.into() {
+            true => dialog.get_filename(),
+            false => None,
+        };
+        dialog.destroy();
+
+        if let Some(dest) = dest.and_then(|p| p.to_str().map(String::from)) {
+            self.download(dest);
+        }
+    }
+
+    fn download(&self, dest: String) {
+        self.clear();
+
+        let progress = gtk::ProgressBar::new();
+        progress.set_show_text(true);
+        progress.set_valign(gtk::Align::Center);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancel_btn = gtk::Button::new_from_icon_name("window-close-symbolic", 1);
+        cancel_btn.set_tooltip_text("Cancel");
+        cancel_btn.set_relief(gtk::ReliefStyle::None);
+        let c = cancel.clone();
+        cancel_btn.connect_clicked(move |btn| {
+            c.store(true, Ordering::SeqCst);
+            btn.set_sensitive(false);
+        });
+
+        self.bx.pack_start(&progress, false, false, 0);
+        self.bx.pack_start(&cancel_btn, false, false, 0);
+        self.bx.show_all();
+
+        let (tx, rx) = mainloop::channel::<Download>();
+        self.backend.send(BKCommand::DownloadFile(self.url.clone(), dest.clone(), cancel, tx)).unwrap();
+
+        let d = self.clone();
+        rx.attach(move |st| {
+            match st {
+                Download::Progress(done, Some(total)) if total > 0 => {
+                    progress.set_fraction(done as f64 / total as f64);
+                    let text = format!("{} / {}", util::human_size(done), util::human_size(total));
+                    progress.set_text(text.as_str());
+                }
+                Download::Progress(done, _) => {
+                    progress.pulse();
+                    progress.set_text(util::human_size(done).as_str());
+                }
+                Download::Done(_) => {
+                    d.show_result("<span alpha=\"60%\">Saved</span>", None);
+                }
+                Download::Canceled => {
+                    d.show_save_button();
+                }
+                Download::Failed(err) => {
+                    let msg = format!("<span color=\"red\">{}</span>",
+                                      util::markup(&format!("Download failed: {}", err)));
+                    d.show_result(&msg, Some(dest.clone()));
+                }
+            };
+        });
+    }
+}
+
+/// Preview of the file @path to attach, with a caption entry and, for
+/// the images, the option to compress them. @send is called with the
+/// caption and the compression if the user accepts, else @cancel.
+pub fn attach_dialog<F, C>(window: &gtk::Window, path: &str, send: F, cancel: C)
+    where F: Fn(String, bool) + 'static,
+          C: Fn() + 'static
+{
+    let dialog = gtk::Dialog::new();
+    dialog.set_title("Send file");
+    dialog.set_transient_for(window);
+    dialog.set_modal(true);
+    dialog.set_resizable(false);
+    dialog.add_button("_Cancel", gtk::ResponseType::Cancel.into());
+    dialog.add_button("_Send", gtk::ResponseType::Accept.into());
+    dialog.set_default_response(gtk::ResponseType::Accept.into());
+
+    let content = dialog.get_content_area();
+    content.set_spacing(6);
+    content.set_border_width(6);
+
+    let mimetype = util::guess_mimetype(path);
+    let name = util::file_name(path);
+
+    let preview = match Pixbuf::new_from_file_at_scale(path, 300, 300, true) {
+        Ok(ref pixbuf) if mimetype.starts_with("image/") => gtk::Image::new_from_pixbuf(pixbuf),
+        _ => gtk::Image::new_from_icon_name("text-x-generic", 6),
+    };
+    let label = gtk::Label::new(name.as_str());
+    label.set_ellipsize(pango::EllipsizeMode::Middle);
+
+    let caption = gtk::Entry::new();
+    caption.set_placeholder_text("Caption");
+    caption.set_activates_default(true);
+
+    let compress = gtk::CheckButton::new_with_label("Compress image");
+    compress.set_active(true);
+
+    content.pack_start(&preview, false, false, 0);
+    content.pack_start(&label, false, false, 0);
+    content.pack_start(&caption, false, false, 0);
+    let can_compress = util::can_compress(&mimetype);
+    if can_compress {
+        content.pack_start(&compress, false, false, 0);
+    }
+
+    dialog.connect_response(move |d, resp| {
+        if resp == gtk::ResponseType::Accept.into() {
+            let text = caption.get_text().unwrap_or(String::new());
+            send(text, can_compress && compress.get_active());
+        } else {
+            cancel();
+        }
+        d.destroy();
+    });
+
+    dialog.show_all();
+}